@@ -0,0 +1,47 @@
+//! Serve an axum app straight through a stab tunnel, with no local port.
+//!
+//! ```bash
+//! cargo run --example axum_tunnel
+//! ```
+
+use axum::{routing::get, Router};
+use stab::{Server, StabConfig, TunnelListener};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let server_cfg = StabConfig {
+        bind_addr: [127, 0, 0, 1].into(),
+        port: 0,
+        ..Default::default()
+    };
+    let server = Server::new(server_cfg).start().await?;
+
+    let client_cfg = StabConfig {
+        port: server.control_addr().port(),
+        client_id_file: std::env::temp_dir()
+            .join("stab-axum-tunnel.id")
+            .to_string_lossy()
+            .into_owned(),
+        ..Default::default()
+    };
+    let listener = TunnelListener::bind(client_cfg, "0=127.0.0.1,name=axum".parse()?).await?;
+    let remote = listener.remote().clone();
+    println!("serving on http://{}", remote);
+
+    let app = Router::new().route("/", get(|| async { "hello from inside the tunnel\n" }));
+    let app = tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mut stream = tokio::net::TcpStream::connect((remote.host.as_str(), remote.port)).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: stab\r\nconnection: close\r\n\r\n")
+        .await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    println!("{}", reply);
+
+    app.abort();
+    server.shutdown();
+    server.wait().await;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Run a stab server and a client in one process and push a request through
+//! the tunnel.
+//!
+//! ```bash
+//! cargo run --example embedded
+//! ```
+
+use std::sync::Arc;
+
+use stab::{Client, ClientEvent, Server, ServerEvent, StabConfig};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // the service to expose: replies with what it got, upper-cased
+    let service = TcpListener::bind("127.0.0.1:0").await?;
+    let service_addr = service.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = service.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 64];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let _ = stream.write_all(&buf[..n].to_ascii_uppercase()).await;
+            });
+        }
+    });
+
+    let server_cfg = StabConfig {
+        bind_addr: [127, 0, 0, 1].into(),
+        port: 0,
+        ..Default::default()
+    };
+    let server = Server::new(server_cfg);
+    let mut server_events = server.subscribe();
+    let server = server.start().await?;
+
+    let mut client_cfg = StabConfig {
+        port: server.control_addr().port(),
+        client_id_file: std::env::temp_dir()
+            .join("stab-embedded.id")
+            .to_string_lossy()
+            .into_owned(),
+        ..Default::default()
+    };
+    let spec = format!("{}=127.0.0.1", service_addr);
+    client_cfg.links.push(Arc::new(spec.parse()?));
+    let client = Client::new(client_cfg);
+    let mut client_events = client.subscribe();
+    let client = client.start().await?;
+
+    let remote = loop {
+        if let ClientEvent::Connected { remote, .. } = client_events.recv().await? {
+            break remote;
+        }
+    };
+    println!("tunnel open on {}", remote);
+
+    let mut stream = TcpStream::connect((remote.host.as_str(), remote.port)).await?;
+    stream.write_all(b"hello through stab").await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    println!("reply: {}", reply);
+    // an open session would hold up the shutdown below for `shutdown_timeout`
+    drop(stream);
+
+    while let Ok(event) = server_events.try_recv() {
+        if let ServerEvent::Connection { tunnel, peer } = event {
+            println!("server saw {} -> {}", peer, tunnel);
+        }
+    }
+
+    client.shutdown();
+    println!("client exit: {:?}", client.wait().await);
+    server.shutdown();
+    server.wait().await;
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! `stab check` and `stab config show`
+
+use toml::{Table, Value};
+
+use crate::config::{
+    build_config, check_config, print_errors, Mode, StabArgs, StabConfig, REDACTED,
+};
+use crate::local::EXIT_CONFIG;
+
+/// Validate the config given by `args`; returns the exit code.
+pub fn check(args: &StabArgs) -> i32 {
+    match check_config(args) {
+        Ok(cfg) => {
+            let source = args.file.as_deref().unwrap_or("command line");
+            match cfg.mode {
+                Mode::Local => println!("{}: ok, local mode, {} links", source, cfg.links.len()),
+                Mode::Server => println!("{}: ok, server mode", source),
+                Mode::Visitor => {
+                    println!("{}: ok, visitor mode, {} links", source, cfg.links.len())
+                }
+            }
+            0
+        }
+        Err(e) => {
+            print_errors(&e);
+            EXIT_CONFIG
+        }
+    }
+}
+
+/// Print the effective config given by `args`; returns the exit code.
+pub fn show(args: &StabArgs) -> i32 {
+    match build_config(args) {
+        Ok(cfg) => {
+            print!("{}", render(&cfg));
+            0
+        }
+        Err(e) => {
+            print_errors(&e);
+            EXIT_CONFIG
+        }
+    }
+}
+
+/// the config as a TOML file, holding the section of its mode only
+fn render(cfg: &StabConfig) -> Table {
+    let mut t = Table::new();
+    t.insert("mode".into(), format!("{:?}", cfg.mode).into());
+    t.insert("port".into(), i64::from(cfg.port).into());
+    t.insert("log".into(), i64::from(cfg.log).into());
+    t.insert("log_path".into(), cfg.log_path.clone().into());
+    if cfg.secret.is_some() {
+        t.insert("secret".into(), REDACTED.into());
+    }
+    t.insert("shutdown_timeout".into(), secs(cfg.shutdown_timeout));
+
+    match cfg.mode {
+        Mode::Local | Mode::Visitor => {
+            t.insert("local".into(), render_local(cfg).into());
+        }
+        Mode::Server => {
+            t.insert("server".into(), render_server(cfg).into());
+        }
+    }
+    t
+}
+
+fn render_local(cfg: &StabConfig) -> Table {
+    let mut t = Table::new();
+    if let Some(to) = &cfg.to {
+        t.insert("to".into(), to.clone().into());
+    }
+    t.insert("retry".into(), i64::from(cfg.retry).into());
+    t.insert("retry_interval".into(), secs(cfg.retry_interval));
+    t.insert("retry_max_interval".into(), secs(cfg.retry_max_interval));
+    t.insert("connect_timeout".into(), secs(cfg.connect_timeout));
+    t.insert("start_timeout".into(), secs(cfg.start_timeout));
+    t.insert("client_id_file".into(), cfg.client_id_file.clone().into());
+
+    let links = cfg
+        .links
+        .iter()
+        .map(|link| {
+            // settings resolved against the globals, so every link is complete
+            let s = link.settings(cfg);
+            let mut l = Table::new();
+            l.insert("local".into(), link.local.to_string().into());
+            l.insert("remote".into(), link.remote.to_string().into());
+            if let Some(b) = link.bind {
+                l.insert("bind".into(), b.to_string().into());
+            }
+            if let Some(n) = &link.name {
+                l.insert("name".into(), n.clone().into());
+            }
+            if let Some(d) = &link.description {
+                l.insert("description".into(), d.clone().into());
+            }
+            if !link.labels.is_empty() {
+                let labels = link
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Value::from(v.clone())))
+                    .collect::<Table>();
+                l.insert("labels".into(), labels.into());
+            }
+            if link.proxy_protocol {
+                l.insert("proxy_protocol".into(), true.into());
+            }
+            if !link.allow.is_empty() {
+                let allow = link.allow.iter().map(|a| Value::from(a.to_string()));
+                l.insert("allow".into(), allow.collect::<Vec<_>>().into());
+            }
+            if let Some(m) = link.max_conns {
+                l.insert("max_conns".into(), i64::from(m).into());
+            }
+            if link.private {
+                l.insert("private".into(), true.into());
+            }
+            if link.visitor_secret.is_some() {
+                l.insert("visitor_secret".into(), REDACTED.into());
+            }
+            if let Some(f) = &link.forward {
+                l.insert("forward".into(), f.to_string().into());
+            }
+            if let Some(u) = &link.socks_user {
+                l.insert("socks_user".into(), u.clone().into());
+            }
+            if link.socks_pass.is_some() {
+                l.insert("socks_pass".into(), REDACTED.into());
+            }
+            if !link.socks_allow.is_empty() {
+                let allow = link.socks_allow.iter().map(|r| Value::from(r.to_string()));
+                l.insert("socks_allow".into(), allow.collect::<Vec<_>>().into());
+            }
+            if link.tls {
+                l.insert("tls".into(), true.into());
+            }
+            if let Some(sni) = &link.tls_sni {
+                l.insert("tls_sni".into(), sni.clone().into());
+            }
+            if let Some(ca) = &link.tls_ca {
+                l.insert("tls_ca".into(), ca.clone().into());
+            }
+            if link.tls_insecure {
+                l.insert("tls_insecure".into(), true.into());
+            }
+            if link.public_tls {
+                l.insert("public_tls".into(), true.into());
+            }
+            l.insert("control_port".into(), i64::from(s.control_port).into());
+            if s.secret.is_some() {
+                l.insert("secret".into(), REDACTED.into());
+            }
+            l.insert("retry".into(), i64::from(s.retry).into());
+            l.insert("retry_interval".into(), secs(s.retry_interval));
+            l.insert("retry_max_interval".into(), secs(s.retry_max_interval));
+            l.insert("connect_timeout".into(), secs(s.connect_timeout.as_secs()));
+            l.insert("start_timeout".into(), secs(s.start_timeout.as_secs()));
+            Value::from(l)
+        })
+        .collect::<Vec<_>>();
+    t.insert("link".into(), links.into());
+    t
+}
+
+fn render_server(cfg: &StabConfig) -> Table {
+    let mut t = Table::new();
+    t.insert("bind_addr".into(), cfg.bind_addr.to_string().into());
+    t.insert("web_port".into(), i64::from(cfg.web_port).into());
+    t.insert("web_bind_addr".into(), cfg.web_bind_addr.to_string().into());
+    t.insert(
+        "data_bind_addr".into(),
+        cfg.data_bind_addr.to_string().into(),
+    );
+    if !cfg.bindable_ips.is_empty() {
+        let ips = cfg
+            .bindable_ips
+            .iter()
+            .map(|ip| Value::from(ip.to_string()));
+        t.insert("bindable_ips".into(), ips.collect::<Vec<_>>().into());
+    }
+    if cfg.web_key.is_some() {
+        t.insert("web_key".into(), REDACTED.into());
+    }
+    t.insert("port_range".into(), cfg.port_range.to_string().into());
+    if let Some(e) = &cfg.exclude_ports {
+        t.insert("exclude_ports".into(), e.to_string().into());
+    }
+    let strategy = format!("{:?}", cfg.port_strategy).to_lowercase();
+    t.insert("port_strategy".into(), strategy.into());
+    t.insert("pool_size".into(), i64::from(cfg.pool_size).into());
+    if let Some(f) = &cfg.sticky_file {
+        t.insert("sticky_file".into(), f.clone().into());
+    }
+    t.insert("reconnect_grace".into(), secs(cfg.reconnect_grace));
+    if !cfg.forward_allow.is_empty() {
+        let allow = cfg.forward_allow.iter().map(|r| Value::from(r.to_string()));
+        t.insert("forward_allow".into(), allow.collect::<Vec<_>>().into());
+    }
+    if let Some(f) = &cfg.tls_cert {
+        t.insert("tls_cert".into(), f.clone().into());
+    }
+    if let Some(f) = &cfg.tls_key {
+        t.insert("tls_key".into(), f.clone().into());
+    }
+
+    if !cfg.reserved.is_empty() {
+        let reserved = cfg
+            .reserved
+            .iter()
+            .map(|r| {
+                let mut rt = Table::new();
+                rt.insert("ports".into(), r.ports.to_string().into());
+                if let Some(c) = &r.client {
+                    rt.insert("client".into(), c.clone().into());
+                }
+                if r.secret.is_some() {
+                    rt.insert("secret".into(), REDACTED.into());
+                }
+                Value::from(rt)
+            })
+            .collect::<Vec<_>>();
+        t.insert("reserved".into(), reserved.into());
+    }
+    t
+}
+
+/// a count of seconds; TOML integers are signed
+fn secs(s: u64) -> Value {
+    Value::Integer(i64::try_from(s).unwrap_or(i64::MAX))
+}
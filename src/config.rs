@@ -1,8 +1,13 @@
 //! the config file
 
 use std::{
-    ops::RangeInclusive,
-    sync::{Arc, OnceLock},
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use anstyle::{
@@ -10,19 +15,124 @@ use anstyle::{
     Color::Ansi,
     Style,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{Parser, ValueEnum};
-
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::{de::IgnoredAny, Deserialize};
 use sha2::{Digest, Sha256};
-use tracing::error;
+use tokio::sync::watch;
+use toml::Spanned;
 use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-/// global configuration
-pub static G_CFG: OnceLock<StabConfig> = OnceLock::new();
+use crate::dest::DestRule;
+use crate::local::EXIT_CONFIG;
+use crate::ports::PortSet;
+
+/// Rebuilds the config on reload, e.g. from the config file and command line.
+pub type ConfigSource = Box<dyn Fn() -> Result<StabConfig> + Send + Sync>;
+
+/// Swappable handle to the current [`StabConfig`] of one server or client.
+///
+/// Readers take a snapshot with [`CfgHandle::get`]; long-running tasks should
+/// take a fresh one for each unit of work so reloads reach them.
+pub struct CfgHandle {
+    cfg: RwLock<Arc<StabConfig>>,
+    reloaded: watch::Sender<()>,
+    source: Option<ConfigSource>,
+}
+
+impl fmt::Debug for CfgHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CfgHandle")
+            .field("cfg", &self.cfg)
+            .field("reloadable", &self.source.is_some())
+            .finish()
+    }
+}
+
+impl CfgHandle {
+    /// Wrap `cfg`; with a `source`, [`CfgHandle::reload`] rebuilds from it.
+    pub fn new(cfg: StabConfig, source: Option<ConfigSource>) -> Self {
+        Self {
+            cfg: RwLock::new(Arc::new(cfg)),
+            reloaded: watch::Sender::new(()),
+            source,
+        }
+    }
+
+    /// current configuration
+    pub fn get(&self) -> Arc<StabConfig> {
+        self.cfg.read().unwrap().clone()
+    }
+
+    /// get notified after each update
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.reloaded.subscribe()
+    }
+
+    /// Rebuild the config from the source and apply it with
+    /// [`CfgHandle::update`].
+    pub fn reload(&self) -> Result<()> {
+        let Some(source) = &self.source else {
+            bail!("no config source to reload from");
+        };
+        self.update(source()?)
+    }
+
+    /// Swap in `new` and wake up subscribers.
+    ///
+    /// Settings bound at startup (mode, listeners, logging) keep their old value
+    /// until restart; a warning names each one that changed.
+    pub fn update(&self, mut new: StabConfig) -> Result<()> {
+        let old = self.get();
+
+        if new.mode != old.mode {
+            bail!("mode cannot change on reload");
+        }
+        let mut restart_only = Vec::new();
+        if new.log != old.log || new.log_path != old.log_path {
+            restart_only.push("log");
+        }
+        if new.client_id_file != old.client_id_file {
+            restart_only.push("client_id_file");
+        }
+        if new.mode == Mode::Server {
+            if new.port != old.port || new.bind_addr != old.bind_addr {
+                restart_only.push("control port");
+            }
+            if new.web_port != old.web_port || new.web_bind_addr != old.web_bind_addr {
+                restart_only.push("web listener");
+            }
+            if new.sticky_file != old.sticky_file {
+                restart_only.push("sticky_file");
+            }
+        }
+        if !restart_only.is_empty() {
+            warn!("needs restart to apply: {}", restart_only.join(", "));
+        }
+        // Keep what the running process was started with so a later reload
+        // compares against what is actually in effect.
+        new.log = old.log;
+        new.log_path = old.log_path.clone();
+        new.client_id_file = old.client_id_file.clone();
+        if new.mode == Mode::Server {
+            new.port = old.port;
+            new.bind_addr = old.bind_addr;
+            new.web_port = old.web_port;
+            new.web_bind_addr = old.web_bind_addr;
+            new.sticky_file = old.sticky_file.clone();
+        }
+
+        *self.cfg.write().unwrap() = Arc::new(new);
+        self.reloaded.send_replace(());
+        info!("config reloaded");
+        Ok(())
+    }
+}
 
-/// global configuration
+/// configuration of a server or client
 #[derive(Debug)]
 pub struct StabConfig {
     /// run mode
@@ -37,8 +147,24 @@ pub struct StabConfig {
     pub secret: Option<String>,
     /// client mode,all link to server
     pub links: Vec<Arc<Link>>,
-    /// server mode,port range (inclusive)
-    pub port_range: RangeInclusive<u16>,
+    /// client mode, default server for links without one
+    pub to: Option<String>,
+    /// server mode,ports handed out automatically or on request
+    pub port_range: PortSet,
+    /// server mode,ports never handed out
+    pub exclude_ports: Option<PortSet>,
+    /// server mode,how free ports are picked
+    pub port_strategy: PortStrategy,
+    /// server mode,ports held back for specific clients
+    pub reserved: Vec<Reservation>,
+    /// server mode,control listener address
+    pub bind_addr: IpAddr,
+    /// server mode,web listener address
+    pub web_bind_addr: IpAddr,
+    /// server mode,data (public tunnel) listener address
+    pub data_bind_addr: IpAddr,
+    /// server mode,extra addresses links may ask to bind on
+    pub bindable_ips: Vec<IpAddr>,
     /// web manage server port
     pub web_port: u16,
     /// web manage page auth key (hashed); None means no auth
@@ -47,8 +173,28 @@ pub struct StabConfig {
     pub pool_size: u16,
     /// local reconnect attempts; `-1` means infinite, `0` means no retry
     pub retry: i32,
-    /// local reconnect interval in seconds
+    /// local initial reconnect delay in seconds; doubles after each failed attempt
     pub retry_interval: u64,
+    /// local upper bound for the reconnect delay in seconds
+    pub retry_max_interval: u64,
+    /// local TCP connect timeout in seconds
+    pub connect_timeout: u64,
+    /// local seconds a work connection waits for the server to pair a client
+    pub start_timeout: u64,
+    /// local file holding the persistent client id, see [`default_client_id_file`]
+    pub client_id_file: String,
+    /// server file persisting sticky port assignments; None keeps them in memory
+    pub sticky_file: Option<String>,
+    /// server seconds to hold a dropped link's port for its client; 0 disables
+    pub reconnect_grace: u64,
+    /// server destinations forward links may reach; empty allows none
+    pub forward_allow: Vec<DestRule>,
+    /// server PEM certificate chain for `public_tls` links
+    pub tls_cert: Option<String>,
+    /// server PEM private key of `tls_cert`
+    pub tls_key: Option<String>,
+    /// seconds to wait for active sessions on shutdown
+    pub shutdown_timeout: u64,
 }
 
 /// the command line arguments
@@ -56,9 +202,13 @@ pub struct StabConfig {
 #[clap(author, version, about)]
 #[command(styles=cmd_help_styles())]
 pub struct StabArgs {
-    /// run mode
+    /// run mode, `check` / `config show` to inspect the config, or `connect`
     #[clap(value_enum)]
-    pub mode: Option<Mode>,
+    pub command: Option<Command>,
+
+    /// what `config` does (`show`), or `<server> <tunnel>` for `connect`
+    #[clap(value_name = "args")]
+    pub operands: Vec<String>,
 
     /// config file
     #[clap(short, long)]
@@ -80,13 +230,34 @@ pub struct StabArgs {
     #[clap(short, long, value_name = "secret")]
     pub secret: Option<String>,
 
-    /// create a link from the local to the server,for example: 8000=www.example.com
-    #[clap(short,long,value_name = "local mode",value_parser=cmd_parse_link)]
-    pub link: Option<Link>,
+    /// read the secret from this file instead of --secret
+    #[clap(long, value_name = "path", conflicts_with = "secret")]
+    pub secret_file: Option<String>,
+
+    /// create a link from the local to the server, repeatable, for example:
+    /// 8000=www.example.com or 8000=www.example.com:9000,name=web,max_conns=10
+    #[clap(short = 'l', long = "link", value_name = "local mode")]
+    pub links: Vec<String>,
+
+    /// default server for --link specs without one
+    #[clap(long, value_name = "host")]
+    pub to: Option<String>,
+
+    /// name of the links given by --link, unless the spec sets one
+    #[clap(long, value_name = "name", requires = "links")]
+    pub name: Option<String>,
+
+    /// description of the links given by --link, unless the spec sets one
+    #[clap(long, value_name = "text", requires = "links")]
+    pub description: Option<String>,
 
-    /// accepted TCP port number range
+    /// label of the links given by --link, repeatable, for example: env=staging
+    #[clap(long = "label", value_name = "key=value", requires = "links", value_parser = cmd_parse_label)]
+    pub labels: Vec<(String, String)>,
+
+    /// accepted TCP ports, for example: 2000-3000,5000,6000-6100
     #[clap(short, long,value_name = "server mode", value_parser = cmd_parse_range)]
-    pub port_range: Option<RangeInclusive<u16>>,
+    pub port_range: Option<PortSet>,
 
     /// web manage server port
     #[clap(short, long, value_name = "server mode")]
@@ -95,7 +266,52 @@ pub struct StabArgs {
     /// prebuild this many idle work connections (0 = on-demand, recommended)
     #[clap(long, value_name = "pool size")]
     pub pool_size: Option<u16>,
+
+    /// also print link events and server errors as JSON lines on stderr
+    #[clap(long)]
+    pub json: bool,
+}
+/// what to run
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum Command {
+    /// local mode
+    Local,
+
+    /// server mode
+    Server,
+
+    /// visitor mode, reach private tunnels through a local listener
+    Visitor,
+
+    /// validate the config and report every problem
+    Check,
+
+    /// inspect the effective config, see `config show`
+    Config,
+
+    /// pipe stdin/stdout through a private tunnel, see `connect <server> <tunnel>`
+    Connect,
+}
+
+impl Command {
+    /// the run mode this command selects, if any
+    pub fn mode(self) -> Option<Mode> {
+        match self {
+            Command::Local => Some(Mode::Local),
+            Command::Server => Some(Mode::Server),
+            Command::Visitor | Command::Connect => Some(Mode::Visitor),
+            Command::Check | Command::Config => None,
+        }
+    }
+}
+
+/// actions of the `config` command
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum ConfigAction {
+    /// print the effective config with secrets redacted
+    Show,
 }
+
 /// the run mode
 #[derive(Copy, Clone, Debug, ValueEnum, Deserialize, PartialEq)]
 pub enum Mode {
@@ -104,24 +320,479 @@ pub enum Mode {
 
     /// server mode
     Server,
+
+    /// visitor mode
+    Visitor,
+}
+
+/// how the server picks a port when the client asks for any
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortStrategy {
+    /// round-robin scan over the port range
+    #[default]
+    Sequential,
+
+    /// random ports from the port range
+    Random,
+}
+
+/// ports that are only handed to a specific client id or secret
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    /// reserved ports
+    pub ports: PortSet,
+    /// client id allowed to use them
+    pub client: Option<String>,
+    /// secret (hashed) allowed to use them
+    pub secret: Option<String>,
+}
+
+impl Reservation {
+    /// whether a client with this id and hashed secret owns the reservation
+    pub fn owned_by(&self, client_id: Option<&str>, secret: Option<&str>) -> bool {
+        let client = self.client.is_some() && self.client.as_deref() == client_id;
+        let secret = self.secret.is_some() && self.secret.as_deref() == secret;
+        client || secret
+    }
 }
 
 /// a link between a local port and a server port
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Address {
-    /// host
+    /// host, IPv6 literals without brackets
     pub host: String,
     /// port
     pub port: u16,
 }
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// where a link's tunneled connections go
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// a TCP address, `8080` or `host:8080`
+    Tcp(Address),
+    /// `socks5`: the local serves SOCKS5 and dials what clients ask for
+    Socks5,
+    /// `unix:/path`, a Unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::Tcp(Address::default())
+    }
+}
+
+impl Target {
+    /// the TCP address, if the target is one
+    pub fn addr(&self) -> Option<&Address> {
+        match self {
+            Self::Tcp(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Socks5 => f.write_str("socks5"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// shown in place of secrets
+pub(crate) const REDACTED: &str = "<redacted>";
+
 /// a link between a local port and a server port
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
 pub struct Link {
     /// local
-    pub local: Address,
+    pub local: Target,
     /// server
     pub remote: Address,
+    /// server-side address to bind the public port on
+    pub bind: Option<IpAddr>,
+    /// short name shown on the server dashboard
+    pub name: Option<String>,
+    /// free-text description
+    pub description: Option<String>,
+    /// key/value labels such as `env=staging`
+    pub labels: BTreeMap<String, String>,
+    /// ask the server to send a PROXY protocol v1 header ahead of each connection
+    pub proxy_protocol: bool,
+    /// public client networks the server accepts; empty allows all
+    pub allow: Vec<IpNet>,
+    /// most concurrent public connections the server accepts
+    pub max_conns: Option<u32>,
+    /// control port of this link's server; global `port` if None
+    pub control_port: Option<u16>,
+    /// hashed secret for this link's server; global `secret` if None
+    pub secret: Option<String>,
+    /// reconnect attempts; `[local]` default if None
+    pub retry: Option<i32>,
+    /// initial reconnect delay in seconds; `[local]` default if None
+    pub retry_interval: Option<u64>,
+    /// reconnect delay cap in seconds; `[local]` default if None
+    pub retry_max_interval: Option<u64>,
+    /// seconds to wait for a TCP connect; `[local]` default if None
+    pub connect_timeout: Option<u64>,
+    /// seconds a work connection waits for the server to pair a client; `[local]` default if None
+    pub start_timeout: Option<u64>,
+    /// register under `name` for visitors instead of opening a public port
+    pub private: bool,
+    /// hashed secret visitors must give; for a visitor, the one it gives
+    pub visitor_secret: Option<String>,
+    /// listen on `local` and have the server dial this address instead
+    pub forward: Option<Address>,
+    /// user SOCKS5 clients log in as
+    pub socks_user: Option<String>,
+    /// password SOCKS5 clients log in with
+    pub socks_pass: Option<String>,
+    /// destinations SOCKS5 clients may reach; empty allows none
+    pub socks_allow: Vec<DestRule>,
+    /// speak TLS to `local`
+    pub tls: bool,
+    /// server name to send and verify instead of the local host
+    pub tls_sni: Option<String>,
+    /// PEM file of CAs to trust instead of the built-in roots
+    pub tls_ca: Option<String>,
+    /// accept any certificate from `local`
+    pub tls_insecure: bool,
+    /// ask the server to terminate TLS on the public port
+    pub public_tls: bool,
+}
+
+/// Debug output ends up in logs, so the secrets are redacted.
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redact = |s: &Option<String>| s.as_ref().map(|_| REDACTED);
+        f.debug_struct("Link")
+            .field("local", &self.local)
+            .field("remote", &self.remote)
+            .field("bind", &self.bind)
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("labels", &self.labels)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("allow", &self.allow)
+            .field("max_conns", &self.max_conns)
+            .field("control_port", &self.control_port)
+            .field("secret", &redact(&self.secret))
+            .field("retry", &self.retry)
+            .field("retry_interval", &self.retry_interval)
+            .field("retry_max_interval", &self.retry_max_interval)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("start_timeout", &self.start_timeout)
+            .field("private", &self.private)
+            .field("visitor_secret", &redact(&self.visitor_secret))
+            .field("forward", &self.forward)
+            .field("socks_user", &self.socks_user)
+            .field("socks_pass", &redact(&self.socks_pass))
+            .field("socks_allow", &self.socks_allow)
+            .field("tls", &self.tls)
+            .field("tls_sni", &self.tls_sni)
+            .field("tls_ca", &self.tls_ca)
+            .field("tls_insecure", &self.tls_insecure)
+            .field("public_tls", &self.public_tls)
+            .finish()
+    }
+}
+
+/// Parses a link spec such as `8000=server.com:9000,name=web`, see the readme.
+impl FromStr for Link {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        parse_link(spec, None)
+    }
+}
+
+/// Settings of a link with the global defaults filled in.
+#[derive(Debug, Clone)]
+pub struct LinkSettings {
+    /// control port of the server
+    pub control_port: u16,
+    /// hashed secret
+    pub secret: Option<String>,
+    /// reconnect attempts; `-1` means infinite, `0` means no retry
+    pub retry: i32,
+    /// initial reconnect delay in seconds
+    pub retry_interval: u64,
+    /// reconnect delay cap in seconds
+    pub retry_max_interval: u64,
+    /// TCP connect timeout
+    pub connect_timeout: Duration,
+    /// how long a work connection waits for `Start`
+    pub start_timeout: Duration,
+}
+
+impl Link {
+    /// resolve per-link settings against `cfg`
+    pub fn settings(&self, cfg: &StabConfig) -> LinkSettings {
+        LinkSettings {
+            control_port: self.control_port.unwrap_or(cfg.port),
+            secret: self.secret.clone().or_else(|| cfg.secret.clone()),
+            retry: self.retry.unwrap_or(cfg.retry),
+            retry_interval: self.retry_interval.unwrap_or(cfg.retry_interval),
+            retry_max_interval: self.retry_max_interval.unwrap_or(cfg.retry_max_interval),
+            connect_timeout: Duration::from_secs(
+                self.connect_timeout.unwrap_or(cfg.connect_timeout),
+            ),
+            start_timeout: Duration::from_secs(self.start_timeout.unwrap_or(cfg.start_timeout)),
+        }
+    }
+}
+
+/// an entry of `links`: a link spec, or a table with the spec and its metadata
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum LinkEntry {
+    /// `8080=server.com:2000`
+    Spec(String),
+    /// `{ link = "8080=server.com:2000", name = "web", labels = { env = "staging" } }`
+    Table(Box<LinkTable>),
+}
+
+/// Table form of a link, used by `[[local.link]]` and by table entries of `links`.
+///
+/// Either `link` (a spec string) or `local`/`remote` gives the addresses; the
+/// other fields override the spec's options and the `[local]` defaults.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LinkTable {
+    /// link spec, same syntax as the string form
+    link: Option<String>,
+    /// local address, `8080` or `host:8080`, `unix:/path` or `socks5`
+    local: Option<String>,
+    /// server address, `server.com:2000`; `to` and port 0 if omitted
+    remote: Option<String>,
+    /// server address to bind the public port on
+    bind: Option<IpAddr>,
+    /// name of a `[local.servers.<name>]` profile
+    server: Option<String>,
+    /// short name
+    name: Option<String>,
+    /// free-text description
+    description: Option<String>,
+    /// key/value labels
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// send a PROXY protocol v1 header to the local target
+    #[serde(default)]
+    proxy_protocol: bool,
+    /// public client IPs or networks to accept
+    #[serde(default)]
+    allow: Vec<String>,
+    /// most concurrent public connections
+    max_conns: Option<u32>,
+    /// control port of this link's server
+    control_port: Option<u16>,
+    /// secret for this link's server
+    secret: Option<String>,
+    /// file holding the secret for this link's server
+    secret_file: Option<String>,
+    /// reconnect attempts
+    retry: Option<i32>,
+    /// initial reconnect delay in seconds
+    retry_interval: Option<u64>,
+    /// maximum reconnect delay in seconds
+    retry_max_interval: Option<u64>,
+    /// TCP connect timeout in seconds
+    connect_timeout: Option<u64>,
+    /// seconds to wait for the server to pair a client
+    start_timeout: Option<u64>,
+    /// register for visitors instead of opening a public port
+    #[serde(default)]
+    private: bool,
+    /// secret visitors must give
+    visitor_secret: Option<String>,
+    /// file holding the visitor secret
+    visitor_secret_file: Option<String>,
+    /// address the server dials for connections to `local`
+    forward: Option<String>,
+    /// user SOCKS5 clients log in as
+    socks_user: Option<String>,
+    /// password SOCKS5 clients log in with
+    socks_pass: Option<String>,
+    /// file holding the SOCKS5 password
+    socks_pass_file: Option<String>,
+    /// destinations SOCKS5 clients may reach
+    #[serde(default)]
+    socks_allow: Vec<String>,
+    /// speak TLS to the local target
+    #[serde(default)]
+    tls: bool,
+    /// TLS server name for the local target
+    tls_sni: Option<String>,
+    /// PEM file of CAs to trust for the local target
+    tls_ca: Option<String>,
+    /// accept any certificate from the local target
+    #[serde(default)]
+    tls_insecure: bool,
+    /// have the server terminate TLS on the public port
+    #[serde(default)]
+    public_tls: bool,
+}
+
+/// A stab server links can share: its host plus the per-link settings that
+/// go with it.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ServerProfile {
+    /// server host
+    host: String,
+    /// control port
+    control_port: Option<u16>,
+    /// secret
+    secret: Option<String>,
+    /// file holding the secret
+    secret_file: Option<String>,
+    /// reconnect attempts
+    retry: Option<i32>,
+    /// initial reconnect delay in seconds
+    retry_interval: Option<u64>,
+    /// maximum reconnect delay in seconds
+    retry_max_interval: Option<u64>,
+    /// TCP connect timeout in seconds
+    connect_timeout: Option<u64>,
+    /// seconds to wait for the server to pair a client
+    start_timeout: Option<u64>,
+}
+
+impl LinkTable {
+    /// Build the link; with `server` set, the profile's host replaces `to`
+    /// and its settings sit between the link's own and the global ones.
+    fn into_link(
+        self,
+        to: Option<&str>,
+        servers: &BTreeMap<String, ServerProfile>,
+    ) -> Result<Link> {
+        let profile = match &self.server {
+            Some(name) => Some(servers.get(name).ok_or_else(|| {
+                anyhow!(
+                    "unknown server {:?}, define it under [local.servers.{}]",
+                    name,
+                    name
+                )
+            })?),
+            None => None,
+        };
+        let to = profile.map(|p| p.host.as_str()).or(to);
+
+        let mut link = match (&self.link, &self.local) {
+            (Some(_), _) if self.local.is_some() || self.remote.is_some() => {
+                bail!("give either `link` or `local`/`remote`, not both")
+            }
+            (Some(spec), _) => parse_link(spec, to)?,
+            (None, Some(local)) => {
+                let mut link = Link {
+                    local: parse_target(local).map_err(|e| anyhow!("local {:?}: {}", local, e))?,
+                    ..Default::default()
+                };
+                link.remote = match &self.remote {
+                    Some(remote) => parse_address(remote, to, Some(0))
+                        .map_err(|e| anyhow!("remote {:?}: {}", remote, e))?,
+                    None => Address {
+                        host: to
+                            .ok_or_else(|| anyhow!("no remote given, set `remote` or `to`"))?
+                            .to_string(),
+                        port: 0,
+                    },
+                };
+                link
+            }
+            (None, None) => bail!("missing `local` (or `link`)"),
+        };
+
+        if self.bind.is_some() {
+            link.bind = self.bind;
+        }
+        if self.name.is_some() {
+            link.name = self.name;
+        }
+        if self.description.is_some() {
+            link.description = self.description;
+        }
+        link.labels.extend(self.labels);
+        link.proxy_protocol |= self.proxy_protocol;
+        for a in &self.allow {
+            link.allow.push(parse_allow(a)?);
+        }
+        if self.max_conns == Some(0) {
+            bail!("max_conns must be positive");
+        }
+        if self.max_conns.is_some() {
+            link.max_conns = self.max_conns;
+        }
+        if let Some(s) = secret_from("secret", self.secret, self.secret_file.as_deref())? {
+            link.secret = Some(s);
+        }
+        link.private |= self.private;
+        if let Some(f) = &self.forward {
+            link.forward = Some(parse_forward(f)?);
+        }
+        if self.socks_user.is_some() {
+            link.socks_user = self.socks_user;
+        }
+        // kept as given: SOCKS5 clients send it in plain text
+        match (self.socks_pass, self.socks_pass_file.as_deref()) {
+            (Some(_), Some(_)) => bail!("give either socks_pass or socks_pass_file, not both"),
+            (Some(p), None) => link.socks_pass = Some(p),
+            (None, Some(path)) => link.socks_pass = Some(read_secret_file(path)?),
+            (None, None) => {}
+        }
+        for rule in &self.socks_allow {
+            link.socks_allow.push(parse_socks_allow(rule)?);
+        }
+        link.tls |= self.tls;
+        if self.tls_sni.is_some() {
+            link.tls_sni = self.tls_sni;
+        }
+        if self.tls_ca.is_some() {
+            link.tls_ca = self.tls_ca;
+        }
+        link.tls_insecure |= self.tls_insecure;
+        link.public_tls |= self.public_tls;
+        let visitor_secret = self.visitor_secret_file.as_deref();
+        if let Some(s) = secret_from("visitor_secret", self.visitor_secret, visitor_secret)? {
+            link.visitor_secret = Some(s);
+        }
+        link.control_port = self.control_port.or(link.control_port);
+        link.retry = self.retry.or(link.retry);
+        link.retry_interval = self.retry_interval.or(link.retry_interval);
+        link.retry_max_interval = self.retry_max_interval.or(link.retry_max_interval);
+        link.connect_timeout = self.connect_timeout.or(link.connect_timeout);
+        link.start_timeout = self.start_timeout.or(link.start_timeout);
+
+        if let Some(p) = profile {
+            if link.secret.is_none() {
+                link.secret = secret_from("secret", p.secret.clone(), p.secret_file.as_deref())
+                    .map_err(|e| anyhow!("[local.servers.{}]: {:#}", self.server.unwrap(), e))?;
+            }
+            link.control_port = link.control_port.or(p.control_port);
+            link.retry = link.retry.or(p.retry);
+            link.retry_interval = link.retry_interval.or(p.retry_interval);
+            link.retry_max_interval = link.retry_max_interval.or(p.retry_max_interval);
+            link.connect_timeout = link.connect_timeout.or(p.connect_timeout);
+            link.start_timeout = link.start_timeout.or(p.start_timeout);
+        }
+        Ok(link)
+    }
 }
 
 /// File configuration
@@ -133,10 +804,14 @@ pub struct FileConfig {
     port: Option<u16>,
     /// the secret
     secret: Option<String>,
+    /// file holding the secret
+    secret_file: Option<Spanned<String>>,
     /// the log level
     log: Option<u8>,
     /// the log save path
-    log_path: Option<String>,
+    log_path: Option<Spanned<String>>,
+    /// seconds to drain sessions on shutdown
+    shutdown_timeout: Option<u64>,
     /// the client config
     local: Option<LocalConfig>,
     /// the server config
@@ -147,13 +822,25 @@ pub struct FileConfig {
 #[derive(Deserialize, Debug)]
 pub struct LocalConfig {
     /// all link to server
-    links: Option<Vec<String>>,
+    links: Option<Vec<Spanned<LinkEntry>>>,
+    /// `[[local.link]]` tables
+    link: Option<Vec<Spanned<LinkTable>>>,
+    /// `[local.servers.<name>]` profiles links refer to with `server = "<name>"`
+    servers: Option<BTreeMap<String, ServerProfile>>,
     /// default server
     to: Option<String>,
     /// reconnect attempts after disconnect; -1 means infinite, 0 means no retry
     retry: Option<i32>,
-    /// reconnect interval in seconds
+    /// initial reconnect delay in seconds
     retry_interval: Option<u64>,
+    /// maximum reconnect delay in seconds
+    retry_max_interval: Option<u64>,
+    /// file holding the persistent client id
+    client_id_file: Option<Spanned<String>>,
+    /// TCP connect timeout in seconds
+    connect_timeout: Option<u64>,
+    /// seconds a work connection waits for the server to pair a client
+    start_timeout: Option<u64>,
 }
 
 /// Server configuration
@@ -161,12 +848,100 @@ pub struct LocalConfig {
 pub struct ServerConfig {
     /// the web port
     web_port: Option<u16>,
+    /// control listener address
+    bind_addr: Option<IpAddr>,
+    /// web listener address
+    web_bind_addr: Option<IpAddr>,
+    /// data listener address
+    data_bind_addr: Option<IpAddr>,
+    /// extra addresses links may ask to bind on
+    bindable_ips: Option<Vec<IpAddr>>,
     /// port range to use
-    port_range: Option<String>,
+    port_range: Option<Spanned<String>>,
     /// pool size
     pool_size: Option<u16>,
     /// web manage page auth key
     web_key: Option<String>,
+    /// file holding the web manage page auth key
+    web_key_file: Option<Spanned<String>>,
+    /// file persisting sticky port assignments
+    sticky_file: Option<Spanned<String>>,
+    /// seconds to hold a dropped link's port
+    reconnect_grace: Option<u64>,
+    /// ports never handed out
+    exclude_ports: Option<Spanned<String>>,
+    /// sequential or random
+    port_strategy: Option<PortStrategy>,
+    /// ports held back for specific clients
+    reserved: Option<Vec<Spanned<ReservedConfig>>>,
+    /// destinations forward links may reach
+    forward_allow: Option<Vec<Spanned<String>>>,
+    /// PEM certificate chain for `public_tls` links
+    tls_cert: Option<Spanned<String>>,
+    /// PEM private key of `tls_cert`
+    tls_key: Option<Spanned<String>>,
+}
+
+/// Reserved ports configuration
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ReservedConfig {
+    /// reserved ports
+    ports: String,
+    /// client id allowed to use them
+    client: Option<String>,
+    /// secret allowed to use them
+    secret: Option<String>,
+    /// file holding the secret allowed to use them
+    secret_file: Option<String>,
+}
+
+impl ReservedConfig {
+    fn into_reservation(self) -> Result<Reservation> {
+        let ports = cmd_parse_range(&self.ports).context("reserved ports")?;
+        let secret = secret_from("secret", self.secret, self.secret_file.as_deref())
+            .context("reserved ports")?;
+        if self.client.is_none() && secret.is_none() {
+            bail!("reserved ports {} need a client or secret", ports);
+        }
+        Ok(Reservation {
+            ports,
+            client: self.client,
+            secret,
+        })
+    }
+}
+
+/// Parse one `STAB_RESERVED` entry: space separated `ports=`, `client=`,
+/// `secret=` and `secret_file=`, as in `[[server.reserved]]`.
+fn parse_reserved(entry: &str) -> Result<Reservation> {
+    let mut r = ReservedConfig::default();
+    for field in entry.split_whitespace() {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected key=value, got {:?}", field))?;
+        let slot = match key {
+            "ports" => {
+                r.ports = value.to_string();
+                continue;
+            }
+            "client" => &mut r.client,
+            "secret" => &mut r.secret,
+            "secret_file" => &mut r.secret_file,
+            _ => bail!(
+                "unknown key {:?}, expected ports, client, secret or secret_file",
+                key
+            ),
+        };
+        *slot = Some(value.to_string());
+    }
+    r.into_reservation()
+}
+
+impl Default for StabConfig {
+    fn default() -> Self {
+        default_config()
+    }
 }
 
 fn default_config() -> StabConfig {
@@ -177,7 +952,18 @@ fn default_config() -> StabConfig {
         log_path: "logs".to_string(),
         secret: None,
         links: Vec::new(),
-        port_range: 1024..=65535,
+        to: None,
+        port_range: PortSet::from_range(1024..=65535),
+        exclude_ports: None,
+        port_strategy: PortStrategy::Sequential,
+        reserved: Vec::new(),
+        bind_addr: Ipv4Addr::UNSPECIFIED.into(),
+        web_bind_addr: Ipv4Addr::UNSPECIFIED.into(),
+        data_bind_addr: Ipv4Addr::UNSPECIFIED.into(),
+        bindable_ips: Vec::new(),
+        forward_allow: Vec::new(),
+        tls_cert: None,
+        tls_key: None,
         web_port: 3400,
         web_key: None,
         // On-demand work connections by default. Pre-pooling (pool_size > 0) can
@@ -186,6 +972,45 @@ fn default_config() -> StabConfig {
         pool_size: 0,
         retry: -1,
         retry_interval: 5,
+        retry_max_interval: 60,
+        connect_timeout: 5,
+        start_timeout: 60,
+        client_id_file: default_client_id_file(),
+        sticky_file: None,
+        reconnect_grace: 0,
+        shutdown_timeout: 30,
+    }
+}
+
+/// `stab/stab.id` in the per-user state directory (`$XDG_STATE_HOME`,
+/// `~/.local/state`, or `%LOCALAPPDATA%` on Windows), so the client id does
+/// not change with the working directory. `stab.id` if there is no home.
+pub fn default_client_id_file() -> String {
+    let var = |name| {
+        std::env::var_os(name)
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+    };
+    let dir = if cfg!(windows) {
+        var("LOCALAPPDATA")
+    } else {
+        var("XDG_STATE_HOME").or_else(|| var("HOME").map(|h| h.join(".local").join("state")))
+    };
+    match dir {
+        Some(dir) => dir.join("stab").join("stab.id").display().to_string(),
+        None => "stab.id".to_string(),
+    }
+}
+
+/// `path` as written in the config file `file`: relative to the file's
+/// directory rather than to the working directory.
+fn beside(file: &str, path: String) -> String {
+    let dir = std::path::absolute(file)
+        .ok()
+        .and_then(|f| f.parent().map(Path::to_path_buf));
+    match dir {
+        Some(dir) => dir.join(path).display().to_string(),
+        None => path,
     }
 }
 
@@ -198,16 +1023,90 @@ pub fn hash_key(key: impl AsRef<[u8]>) -> String {
     hash_secret(key)
 }
 
-/// Parse CLI / config file and store into [`G_CFG`]. Must be called first.
-pub fn init_config() {
-    let args = StabArgs::parse();
+/// The hashed secret given inline or through `<what>_file`; setting both is
+/// an error.
+fn secret_from(what: &str, inline: Option<String>, file: Option<&str>) -> Result<Option<String>> {
+    match (inline, file) {
+        (Some(_), Some(_)) => bail!("give either {} or {}_file, not both", what, what),
+        (Some(s), None) => Ok(Some(hash_secret(s))),
+        (None, Some(path)) => Ok(Some(hash_secret(read_secret_file(path)?))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// read a secret from `path`, without the trailing newline
+fn read_secret_file(path: &str) -> Result<String> {
+    let s =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("read secret file {:?}: {}", path, e))?;
+    let s = s.trim_end_matches(['\r', '\n']);
+    if s.is_empty() {
+        bail!("secret file {:?} is empty", path);
+    }
+    Ok(s.to_string())
+}
+
+/// Build the config from `args`, exiting with [`EXIT_CONFIG`] if it is invalid.
+pub fn init_config(args: &StabArgs) -> StabConfig {
+    build_config(args).unwrap_or_else(|e| {
+        print_errors(&e);
+        std::process::exit(EXIT_CONFIG);
+    })
+}
+
+/// print a config error to stderr, one `error:` line per problem
+pub fn print_errors(e: &anyhow::Error) {
+    match e.downcast_ref::<ConfigErrors>() {
+        Some(errors) => errors.iter().for_each(|e| eprintln!("error: {}", e)),
+        None => eprintln!("error: {:#}", e),
+    }
+}
+
+/// Reload `cfg` on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(cfg: Arc<CfgHandle>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let hup = signal(SignalKind::hangup());
+    let Ok(mut hup) = hup else {
+        error!("listen SIGHUP failed: {}", hup.unwrap_err());
+        return;
+    };
+    while hup.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        if let Err(e) = cfg.reload() {
+            error!("reload config failed: {:#}", e);
+        }
+    }
+}
+
+/// SIGHUP does not exist here; reload through the web API instead.
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_cfg: Arc<CfgHandle>) {}
+
+/// Build the config from defaults, the config file and the command line.
+///
+/// Every problem is collected; the error is a [`ConfigErrors`] listing them.
+/// Problems the process can run with, such as a missing `log_path` directory,
+/// are printed as warnings.
+pub fn build_config(args: &StabArgs) -> Result<StabConfig> {
+    build(args, false)
+}
+
+/// Like [`build_config`], for `stab check`: warnings count as errors.
+pub fn check_config(args: &StabArgs) -> Result<StabConfig> {
+    build(args, true)
+}
+
+fn build(args: &StabArgs, strict: bool) -> Result<StabConfig> {
+    let mut errors = ConfigErrors::default();
     let mut stab_config = default_config();
 
     if let Some(file) = &args.file {
-        init_by_config_file(file, &mut stab_config);
+        load_config_file(file, &mut stab_config, &mut errors);
     }
+    load_env(&mut stab_config, &mut errors);
 
-    if let Some(m) = args.mode {
+    if let Some(m) = args.command.and_then(Command::mode) {
         stab_config.mode = m;
     }
     if let Some(c) = args.control_port {
@@ -216,63 +1115,593 @@ pub fn init_config() {
     if let Some(l) = args.log {
         stab_config.log = l;
     }
-    if let Some(p) = args.log_path {
-        stab_config.log_path = p;
+    if let Some(p) = &args.log_path {
+        stab_config.log_path = p.clone();
     }
     if let Some(p) = args.pool_size {
         stab_config.pool_size = p;
     }
-    if let Some(secret) = args.secret {
-        stab_config.secret = Some(hash_secret(secret));
+    match secret_from("secret", args.secret.clone(), args.secret_file.as_deref()) {
+        Ok(Some(s)) => stab_config.secret = Some(s),
+        Ok(None) => {}
+        Err(e) => errors.push(format!("--secret-file: {:#}", e)),
+    }
+    if let Some(to) = &args.to {
+        stab_config.to = Some(to.clone());
     }
-    if let Some(link) = args.link {
+    for raw in &args.links {
+        let mut link = match parse_link(raw, stab_config.to.as_deref()) {
+            Ok(link) => link,
+            Err(e) => {
+                errors.push(format!("--link: {:#}", e));
+                continue;
+            }
+        };
+        if link.name.is_none() {
+            link.name = args.name.clone();
+        }
+        if link.description.is_none() {
+            link.description = args.description.clone();
+        }
+        for (k, v) in &args.labels {
+            link.labels.entry(k.clone()).or_insert_with(|| v.clone());
+        }
         stab_config.links.push(Arc::new(link));
     }
-    if let Some(range) = args.port_range {
-        stab_config.port_range = range;
+    if let Some(range) = &args.port_range {
+        stab_config.port_range = range.clone();
     }
     if let Some(w) = args.web_port {
         stab_config.web_port = w;
     }
 
-    if stab_config.mode == Mode::Local && stab_config.links.is_empty() {
-        panic!("No provide links");
+    validate(&stab_config, args, &mut errors);
+    if strict {
+        let warnings = std::mem::take(&mut errors.warnings);
+        errors.errors.extend(warnings);
+    } else {
+        errors
+            .warnings
+            .iter()
+            .for_each(|w| eprintln!("warning: {}", w));
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    Ok(stab_config)
+}
+
+/// Apply `STAB_*` environment variables, which sit between the file and the
+/// command line. Empty variables are ignored.
+fn load_env(cfg: &mut StabConfig, errors: &mut ConfigErrors) {
+    let mut env = EnvLayer { errors };
+
+    if let Some(m) = env.get("STAB_MODE") {
+        match Mode::from_str(&m, true) {
+            Ok(m) => cfg.mode = m,
+            Err(_) => env.error("STAB_MODE", "expected local, server or visitor"),
+        }
+    }
+    env.parse("STAB_PORT", &mut cfg.port);
+    env.parse("STAB_LOG", &mut cfg.log);
+    env.parse("STAB_LOG_PATH", &mut cfg.log_path);
+    env.secret("STAB_SECRET", &mut cfg.secret);
+    env.parse("STAB_SHUTDOWN_TIMEOUT", &mut cfg.shutdown_timeout);
+
+    env.parse("STAB_WEB_PORT", &mut cfg.web_port);
+    env.parse("STAB_BIND_ADDR", &mut cfg.bind_addr);
+    env.parse("STAB_WEB_BIND_ADDR", &mut cfg.web_bind_addr);
+    env.parse("STAB_DATA_BIND_ADDR", &mut cfg.data_bind_addr);
+    if let Some(v) = env.get("STAB_BINDABLE_IPS") {
+        match v.split(',').map(|ip| ip.trim().parse()).collect() {
+            Ok(ips) => cfg.bindable_ips = ips,
+            Err(e) => env.error("STAB_BINDABLE_IPS", e),
+        }
+    }
+    env.parse("STAB_PORT_RANGE", &mut cfg.port_range);
+    if let Some(v) = env.get("STAB_EXCLUDE_PORTS") {
+        match v.parse() {
+            Ok(p) => cfg.exclude_ports = Some(p),
+            Err(e) => env.error("STAB_EXCLUDE_PORTS", e),
+        }
+    }
+    if let Some(v) = env.get("STAB_PORT_STRATEGY") {
+        match v.as_str() {
+            "sequential" => cfg.port_strategy = PortStrategy::Sequential,
+            "random" => cfg.port_strategy = PortStrategy::Random,
+            _ => env.error("STAB_PORT_STRATEGY", "expected sequential or random"),
+        }
+    }
+    env.parse("STAB_POOL_SIZE", &mut cfg.pool_size);
+    env.secret("STAB_WEB_KEY", &mut cfg.web_key);
+    if let Some(f) = env.get("STAB_STICKY_FILE") {
+        cfg.sticky_file = Some(f);
+    }
+    env.parse("STAB_RECONNECT_GRACE", &mut cfg.reconnect_grace);
+    if let Some(v) = env.get("STAB_RESERVED") {
+        let entries = v.split(';').map(str::trim).filter(|r| !r.is_empty());
+        match entries.map(parse_reserved).collect() {
+            Ok(reserved) => cfg.reserved = reserved,
+            Err(e) => env.error("STAB_RESERVED", format!("{:#}", e)),
+        }
+    }
+    if let Some(v) = env.get("STAB_FORWARD_ALLOW") {
+        let rules = v.split(';').map(str::trim).filter(|r| !r.is_empty());
+        match rules.map(str::parse).collect() {
+            Ok(rules) => cfg.forward_allow = rules,
+            Err(e) => env.error("STAB_FORWARD_ALLOW", e),
+        }
+    }
+    if let Some(f) = env.get("STAB_TLS_CERT") {
+        cfg.tls_cert = Some(f);
+    }
+    if let Some(f) = env.get("STAB_TLS_KEY") {
+        cfg.tls_key = Some(f);
+    }
+
+    if let Some(to) = env.get("STAB_TO") {
+        cfg.to = Some(to);
     }
+    env.parse("STAB_RETRY", &mut cfg.retry);
+    env.parse("STAB_RETRY_INTERVAL", &mut cfg.retry_interval);
+    env.parse("STAB_RETRY_MAX_INTERVAL", &mut cfg.retry_max_interval);
+    env.parse("STAB_CONNECT_TIMEOUT", &mut cfg.connect_timeout);
+    env.parse("STAB_START_TIMEOUT", &mut cfg.start_timeout);
+    env.parse("STAB_CLIENT_ID_FILE", &mut cfg.client_id_file);
+    if let Some(links) = env.get("STAB_LINKS") {
+        for raw in links.split(';').map(str::trim).filter(|l| !l.is_empty()) {
+            match parse_link(raw, cfg.to.as_deref()) {
+                Ok(link) => cfg.links.push(Arc::new(link)),
+                Err(e) => env.error("STAB_LINKS", format!("{:#}", e)),
+            }
+        }
+    }
+}
 
-    G_CFG.get_or_init(|| stab_config);
+/// reads `STAB_*` variables, recording bad values
+struct EnvLayer<'a> {
+    errors: &'a mut ConfigErrors,
 }
 
-/// init config with file
-pub fn init_by_config_file(file: &str, stab_config: &mut StabConfig) {
-    let cfg_str = std::fs::read_to_string(file);
+impl EnvLayer<'_> {
+    /// the variable's value, if set and not empty
+    fn get(&mut self, name: &str) -> Option<String> {
+        match std::env::var(name) {
+            Ok(v) if !v.trim().is_empty() => Some(v.trim().to_string()),
+            Ok(_) | Err(std::env::VarError::NotPresent) => None,
+            Err(e) => {
+                self.error(name, e);
+                None
+            }
+        }
+    }
 
-    let Ok(cfg_str) = cfg_str else {
-        panic!("{:?}", cfg_str.unwrap_err());
-    };
+    fn error(&mut self, name: &str, e: impl fmt::Display) {
+        self.errors.push(format!("{}: {}", name, e));
+    }
+
+    /// overwrite `slot` with the parsed variable
+    fn parse<T: FromStr>(&mut self, name: &str, slot: &mut T)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Some(v) = self.get(name) {
+            match v.parse() {
+                Ok(v) => *slot = v,
+                Err(e) => self.error(name, e),
+            }
+        }
+    }
+
+    /// a secret from `<name>` or the file named by `<name>_FILE`, hashed
+    fn secret(&mut self, name: &str, slot: &mut Option<String>) {
+        let file_var = format!("{}_FILE", name);
+        let file = self.get(&file_var);
+        // keep the inline value out of error messages
+        let inline = std::env::var(name).ok().filter(|v| !v.is_empty());
+        match (inline, file) {
+            (Some(_), Some(_)) => self.error(
+                name,
+                format!("give either {} or {}, not both", name, file_var),
+            ),
+            (Some(s), None) => *slot = Some(hash_secret(s)),
+            (None, Some(path)) => match read_secret_file(&path) {
+                Ok(s) => *slot = Some(hash_secret(s)),
+                Err(e) => self.error(&file_var, format!("{:#}", e)),
+            },
+            (None, None) => {}
+        }
+    }
+}
+
+/// SOCKS5 options belong to `socks5` links, which need a login and an allowlist
+fn check_socks(i: usize, link: &Link, errors: &mut ConfigErrors) {
+    let options = link.socks_user.is_some() || link.socks_pass.is_some();
+    if link.local != Target::Socks5 {
+        if options || !link.socks_allow.is_empty() {
+            errors.push(format!(
+                "link {}: socks_* options only apply to socks5 links",
+                i + 1
+            ));
+        }
+        return;
+    }
+    if link.socks_user.is_none() || link.socks_pass.is_none() {
+        errors.push(format!(
+            "link {}: socks5 links need socks_user and socks_pass",
+            i + 1
+        ));
+    }
+    if link.socks_allow.is_empty() {
+        errors.push(format!("link {}: socks5 links need socks_allow", i + 1));
+    }
+    if link.proxy_protocol {
+        errors.push(format!(
+            "link {}: socks5 links cannot use proxy_protocol",
+            i + 1
+        ));
+    }
+}
 
-    let file_config = toml::from_str(&cfg_str);
+/// TLS options belong to `tls` links, which dial a TCP address
+fn check_tls(i: usize, link: &Link, errors: &mut ConfigErrors) {
+    if !link.tls {
+        if link.tls_sni.is_some() || link.tls_ca.is_some() || link.tls_insecure {
+            errors.push(format!("link {}: tls_* options need tls", i + 1));
+        }
+        return;
+    }
+    if link.forward.is_some() {
+        errors.push(format!(
+            "link {}: tls does not apply to forward links",
+            i + 1
+        ));
+    } else if link.local.addr().is_none() {
+        errors.push(format!("link {}: tls needs a TCP local target", i + 1));
+    }
+    if let Err(e) = crate::tls::client_config(link) {
+        errors.push(format!("link {}: {:#}", i + 1, e));
+    }
+}
 
-    if let Err(e) = file_config {
-        panic!("parse config file failed {}", e);
+/// checks that need the merged config: ranges, paths and options that clash
+fn validate(cfg: &StabConfig, args: &StabArgs, errors: &mut ConfigErrors) {
+    if !(1..=5).contains(&cfg.log) {
+        errors.warn(format!(
+            "log level {} is out of range 1-5, logging everything",
+            cfg.log
+        ));
+    }
+    check_dir("log_path", &cfg.log_path, errors);
+
+    match cfg.mode {
+        Mode::Local => {
+            if cfg.links.is_empty() && errors.is_empty() {
+                errors.push("No provide links");
+            }
+            let mut seen = std::collections::HashMap::new();
+            for (i, link) in cfg.links.iter().enumerate() {
+                check_socks(i, link, errors);
+                check_tls(i, link, errors);
+                if link.forward.is_some() {
+                    if link.local.addr().is_none() {
+                        errors.push(format!(
+                            "link {}: forward links listen on an address",
+                            i + 1
+                        ));
+                    }
+                    if link.public_tls {
+                        errors.push(format!("link {}: forward links have no public port", i + 1));
+                    }
+                    if link.private {
+                        errors.push(format!("link {}: forward links cannot be private", i + 1));
+                    }
+                    if link.remote.port != 0 {
+                        errors.push(format!("link {}: forward links take no server port", i + 1));
+                    }
+                    continue;
+                }
+                if link.private {
+                    if link.public_tls {
+                        errors.push(format!("link {}: private links have no public port", i + 1));
+                    }
+                    if link.name.is_none() {
+                        errors.push(format!("link {}: private links need a name", i + 1));
+                    }
+                    if link.remote.port != 0 {
+                        errors.push(format!("link {}: private links take no server port", i + 1));
+                    }
+                    continue;
+                }
+                // port 0 asks for any port, so only fixed ports can clash
+                if link.remote.port == 0 {
+                    continue;
+                }
+                let key = (&link.remote, link.bind, link.settings(cfg).control_port);
+                if let Some(first) = seen.insert(key, i) {
+                    errors.push(format!(
+                        "links {} and {} both ask for {}",
+                        first + 1,
+                        i + 1,
+                        link.remote
+                    ));
+                }
+            }
+        }
+        Mode::Visitor => {
+            if cfg.links.is_empty() && errors.is_empty() {
+                errors.push("No provide links");
+            }
+            for (i, link) in cfg.links.iter().enumerate() {
+                if link.name.is_none() {
+                    errors.push(format!(
+                        "link {}: visitor links need the name of a private tunnel",
+                        i + 1
+                    ));
+                }
+                if link.forward.is_some() {
+                    errors.push(format!(
+                        "link {}: forward only applies to local mode",
+                        i + 1
+                    ));
+                }
+                if link.local.addr().is_none() {
+                    errors.push(format!(
+                        "link {}: visitor links listen on an address",
+                        i + 1
+                    ));
+                }
+                if link.tls || link.public_tls {
+                    errors.push(format!(
+                        "link {}: tls options only apply to local mode",
+                        i + 1
+                    ));
+                }
+            }
+        }
+        Mode::Server => {
+            if !args.links.is_empty() {
+                errors.push("--link only applies to local mode");
+            }
+            if let Some(f) = &cfg.sticky_file {
+                check_parent("sticky_file", f, errors);
+            }
+            if cfg.port == cfg.web_port
+                && (cfg.bind_addr == cfg.web_bind_addr
+                    || cfg.bind_addr.is_unspecified()
+                    || cfg.web_bind_addr.is_unspecified())
+            {
+                errors.push(format!("port and web_port both use {}", cfg.port));
+            }
+            if let Some(ex) = &cfg.exclude_ports {
+                if cfg.port_range.iter().all(|p| ex.contains(p)) {
+                    errors.push(format!(
+                        "exclude_ports {} leaves nothing of port_range {}",
+                        ex, cfg.port_range
+                    ));
+                }
+            }
+            match (&cfg.tls_cert, &cfg.tls_key) {
+                (Some(cert), Some(key)) => {
+                    if let Err(e) = crate::tls::acceptor(cert, key) {
+                        errors.push(format!("{:#}", e));
+                    }
+                }
+                (None, None) => {}
+                _ => errors.push("tls_cert and tls_key go together"),
+            }
+        }
     }
+}
+
+/// `path` should be a directory, or not exist yet
+fn check_dir(what: &str, path: &str, errors: &mut ConfigErrors) {
+    let p = Path::new(path);
+    if p.exists() && !p.is_dir() {
+        errors.warn(format!("{} {:?} is not a directory", what, path));
+    }
+}
+
+/// the directory `path` lives in should exist, and `path` should not be a
+/// directory; writing it fails otherwise
+fn check_parent(what: &str, path: &str, errors: &mut ConfigErrors) {
+    let p = Path::new(path);
+    if p.is_dir() {
+        errors.warn(format!("{} {:?} is a directory", what, path));
+        return;
+    }
+    match p.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            errors.warn(format!(
+                "{} {:?}: directory {:?} does not exist",
+                what, path, dir
+            ));
+        }
+        _ => {}
+    }
+}
 
-    let file_config: FileConfig = file_config.unwrap();
+/// Problems found while building a config, each as `file:line:col: message`
+/// when the place is known. All of them are collected before giving up.
+#[derive(Debug, Default)]
+pub struct ConfigErrors {
+    errors: Vec<String>,
+    /// problems only `stab check` fails on
+    warnings: Vec<String>,
+}
 
-    file_config.mode.map(|a| stab_config.mode = a);
-    file_config.port.map(|a| stab_config.port = a);
-    file_config.log.map(|l| stab_config.log = l);
-    file_config.log_path.map(|p| stab_config.log_path = p);
+impl ConfigErrors {
+    /// whether nothing went wrong
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
 
-    if let Some(s) = file_config.secret {
-        stab_config.secret = Some(hash_secret(s));
+    /// each problem, one per line
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.errors.iter().map(String::as_str)
+    }
+
+    fn push(&mut self, e: impl fmt::Display) {
+        self.errors.push(e.to_string());
+    }
+
+    fn warn(&mut self, e: impl fmt::Display) {
+        self.warnings.push(e.to_string());
+    }
+
+    /// record `e` at byte `offset` of the file `src` was read from
+    fn at(&mut self, file: &str, src: &str, offset: usize, e: impl fmt::Display) {
+        let (line, col) = line_col(src, offset);
+        self.errors
+            .push(format!("{}:{}:{}: {}", file, line, col, e));
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.errors.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// 1-based line and column of byte `offset` in `src`
+fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset.min(src.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// init config with file
+pub fn init_by_config_file(file: &str, stab_config: &mut StabConfig) -> Result<()> {
+    let mut errors = ConfigErrors::default();
+    load_config_file(file, stab_config, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.into())
+    }
+}
+
+/// Apply `file` onto `stab_config`, recording every problem in `errors`.
+fn load_config_file(file: &str, stab_config: &mut StabConfig, errors: &mut ConfigErrors) {
+    let cfg_str = match std::fs::read_to_string(file) {
+        Ok(s) => s,
+        Err(e) => return errors.push(format!("{}: read failed: {}", file, e)),
+    };
+
+    // reported in file order once every section is read
+    let mut located = unknown_keys(&cfg_str);
+    let file_config: FileConfig = match toml::from_str(&cfg_str) {
+        Ok(c) => c,
+        Err(e) => {
+            let msg = e.message().trim_end().replace('\n', ", ");
+            match e.span() {
+                Some(span) => located.push((span.start, anyhow!(msg))),
+                None => errors.push(format!("{}: {}", file, msg)),
+            }
+            return report_located(file, &cfg_str, located, errors);
+        }
+    };
+    let mut err_at = |span: std::ops::Range<usize>, e: anyhow::Error| located.push((span.start, e));
+
+    if let Some(m) = file_config.mode {
+        stab_config.mode = m;
+    }
+    if let Some(p) = file_config.port {
+        stab_config.port = p;
+    }
+    if let Some(l) = file_config.log {
+        stab_config.log = l;
+    }
+    if let Some(p) = file_config.log_path {
+        stab_config.log_path = p.into_inner();
+    }
+    if let Some(t) = file_config.shutdown_timeout {
+        stab_config.shutdown_timeout = t;
+    }
+
+    let span = file_config.secret_file.as_ref().map_or(0..0, |f| f.span());
+    let secret_file = file_config
+        .secret_file
+        .as_ref()
+        .map(|f| f.get_ref().as_str());
+    match secret_from("secret", file_config.secret, secret_file) {
+        Ok(Some(s)) => stab_config.secret = Some(s),
+        Ok(None) => {}
+        Err(e) => err_at(span, e),
     }
     if let Some(s) = file_config.server {
-        s.web_port.map(|p| stab_config.web_port = p);
-        s.pool_size.map(|p| stab_config.pool_size = p);
-        let p_range = s.port_range.unwrap_or("1024-65535".to_string());
-        stab_config.port_range = cmd_parse_range(p_range.as_str()).unwrap();
-        if let Some(k) = s.web_key {
-            stab_config.web_key = Some(hash_secret(k));
+        if let Some(p) = s.web_port {
+            stab_config.web_port = p;
+        }
+        if let Some(a) = s.bind_addr {
+            stab_config.bind_addr = a;
+        }
+        if let Some(a) = s.web_bind_addr {
+            stab_config.web_bind_addr = a;
+        }
+        if let Some(a) = s.data_bind_addr {
+            stab_config.data_bind_addr = a;
+        }
+        if let Some(a) = s.bindable_ips {
+            stab_config.bindable_ips = a;
+        }
+        if let Some(p) = s.pool_size {
+            stab_config.pool_size = p;
+        }
+        if let Some(r) = s.port_range {
+            match cmd_parse_range(r.get_ref()) {
+                Ok(p) => stab_config.port_range = p,
+                Err(e) => err_at(r.span(), e.context("port_range")),
+            }
+        }
+        let span = s.web_key_file.as_ref().map_or(0..0, |f| f.span());
+        let key_file = s.web_key_file.as_ref().map(|f| f.get_ref().as_str());
+        match secret_from("web_key", s.web_key, key_file) {
+            Ok(Some(k)) => stab_config.web_key = Some(k),
+            Ok(None) => {}
+            Err(e) => err_at(span, e),
+        }
+        if let Some(f) = s.sticky_file {
+            stab_config.sticky_file = Some(f.into_inner());
+        }
+        if let Some(g) = s.reconnect_grace {
+            stab_config.reconnect_grace = g;
+        }
+        if let Some(e) = s.exclude_ports {
+            match cmd_parse_range(e.get_ref()) {
+                Ok(p) => stab_config.exclude_ports = Some(p),
+                Err(err) => err_at(e.span(), err.context("exclude_ports")),
+            }
+        }
+        if let Some(p) = s.port_strategy {
+            stab_config.port_strategy = p;
+        }
+        for r in s.reserved.unwrap_or_default() {
+            let span = r.span();
+            match r.into_inner().into_reservation() {
+                Ok(r) => stab_config.reserved.push(r),
+                Err(e) => err_at(span, e),
+            }
+        }
+        for rule in s.forward_allow.unwrap_or_default() {
+            match rule.get_ref().parse() {
+                Ok(r) => stab_config.forward_allow.push(r),
+                Err(e) => err_at(
+                    rule.span(),
+                    e.context(format!("forward_allow {:?}", rule.get_ref())),
+                ),
+            }
+        }
+        if let Some(f) = s.tls_cert {
+            stab_config.tls_cert = Some(f.into_inner());
+        }
+        if let Some(f) = s.tls_key {
+            stab_config.tls_key = Some(f.into_inner());
         }
     }
 
@@ -283,25 +1712,132 @@ pub fn init_by_config_file(file: &str, stab_config: &mut StabConfig) {
         if let Some(i) = c.retry_interval {
             stab_config.retry_interval = i;
         }
-        let links = c.links.unwrap_or_default();
-        for link in links {
-            let lin = parse_link(&link, c.to.as_deref());
-
-            let Ok(lin) = lin else {
-                panic!("parse link failed: {:?}", link);
+        if let Some(i) = c.retry_max_interval {
+            stab_config.retry_max_interval = i;
+        }
+        if let Some(f) = c.client_id_file {
+            stab_config.client_id_file = beside(file, f.into_inner());
+        }
+        if let Some(t) = c.connect_timeout {
+            stab_config.connect_timeout = t;
+        }
+        if let Some(t) = c.start_timeout {
+            stab_config.start_timeout = t;
+        }
+        stab_config.to = c.to.clone();
+        let to = c.to.as_deref();
+        let servers = c.servers.unwrap_or_default();
+        for entry in c.links.unwrap_or_default() {
+            let span = entry.span();
+            let lin = match entry.into_inner() {
+                LinkEntry::Spec(link) => parse_link(&link, to),
+                LinkEntry::Table(t) => t.into_link(to, &servers),
             };
+            match lin {
+                Ok(lin) => stab_config.links.push(Arc::new(lin)),
+                Err(e) => err_at(span, e),
+            }
+        }
+        for (i, t) in c.link.unwrap_or_default().into_iter().enumerate() {
+            let span = t.span();
+            match t.into_inner().into_link(to, &servers) {
+                Ok(lin) => stab_config.links.push(Arc::new(lin)),
+                Err(e) => err_at(span, e.context(format!("[[local.link]] #{}", i + 1))),
+            }
+        }
+    }
+
+    report_located(file, &cfg_str, located, errors);
+}
 
-            stab_config.links.push(Arc::new(lin));
+/// Record `located` problems of `file` in file order.
+fn report_located(
+    file: &str,
+    src: &str,
+    mut located: Vec<(usize, anyhow::Error)>,
+    errors: &mut ConfigErrors,
+) {
+    located.sort_by_key(|(offset, _)| *offset);
+    for (offset, e) in located {
+        errors.at(file, src, offset, format!("{:#}", e));
+    }
+}
+
+/// Keys of the top level, `[local]` and `[server]` that the config file does
+/// not know, with their byte offsets. A misspelled key would otherwise be
+/// dropped without a word; the tables below them deny unknown keys
+/// themselves.
+fn unknown_keys(src: &str) -> Vec<(usize, anyhow::Error)> {
+    type Keys = BTreeMap<Spanned<String>, IgnoredAny>;
+    #[derive(Deserialize)]
+    struct Sections {
+        local: Option<Keys>,
+        server: Option<Keys>,
+    }
+
+    // syntax errors are left to the full parse
+    let Ok(top) = toml::from_str::<Keys>(src) else {
+        return Vec::new();
+    };
+    let sections = toml::from_str::<Sections>(src).ok();
+    let (local, server) = sections.map_or((None, None), |s| (s.local, s.server));
+    let checks = [
+        ("", Some(top), field_names::<FileConfig>()),
+        ("[local] ", local, field_names::<LocalConfig>()),
+        ("[server] ", server, field_names::<ServerConfig>()),
+    ];
+    let mut unknown = Vec::new();
+    for (section, keys, known) in checks {
+        for key in keys.unwrap_or_default().into_keys() {
+            if !known.contains(&key.get_ref().as_str()) {
+                let e = anyhow!("{}unknown key {:?}", section, key.get_ref());
+                unknown.push((key.span().start, e));
+            }
         }
     }
+    unknown
+}
+
+/// Field names the derived `Deserialize` of struct `T` accepts.
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    use serde::de::{value::Error, Error as _, Visitor};
+
+    /// stops at `deserialize_struct`, keeping the field names it is given
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for Fields<'_> {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+            Err(Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Error> {
+            *self.0 = fields;
+            Err(Error::custom("fields read"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Fields(&mut fields));
+    fields
 }
 
 /// config the log
-pub fn init_log() {
+pub fn init_log(cfg: &StabConfig) {
     let timer = tracing_subscriber::fmt::time::ChronoLocal::new("%Y-%m-%d %H:%M:%S".to_owned());
 
-    let cfg = G_CFG.get().unwrap();
-
     let logfile = tracing_appender::rolling::daily(&cfg.log_path, "stab.log");
 
     // console Layer
@@ -311,7 +1847,7 @@ pub fn init_log() {
         .with_line_number(true)
         .with_writer(std::io::stdout)
         .with_ansi(true)
-        .with_filter(log_level());
+        .with_filter(log_level(cfg.log));
 
     // file Layer
     let file_layer = tracing_subscriber::fmt::layer()
@@ -320,7 +1856,7 @@ pub fn init_log() {
         .with_line_number(true)
         .with_writer(logfile)
         .with_ansi(false)
-        .with_filter(log_level());
+        .with_filter(log_level(cfg.log));
 
     tracing_subscriber::registry()
         .with(file_layer)
@@ -328,11 +1864,9 @@ pub fn init_log() {
         .init();
 }
 
-/// get the log level from the config
-fn log_level() -> LevelFilter {
-    let f_cfg = G_CFG.get().unwrap();
-
-    match f_cfg.log {
+/// map the configured log level to a filter
+fn log_level(log: u8) -> LevelFilter {
+    match log {
         1 => LevelFilter::ERROR,
         2 => LevelFilter::WARN,
         3 => LevelFilter::INFO,
@@ -354,120 +1888,442 @@ fn cmd_help_styles() -> clap::builder::Styles {
         .placeholder(Style::new().fg_color(Some(Ansi(BrightCyan))))
 }
 
-/// parse port range (inclusive on both ends)
-fn cmd_parse_range(s: &str) -> Result<RangeInclusive<u16>> {
-    let err_msg = anyhow!("parse port range failed");
+/// parse port list, for example `2000-3000,5000` (ranges inclusive on both ends)
+fn cmd_parse_range(s: &str) -> Result<PortSet> {
+    s.parse::<PortSet>()
+        .map_err(|e| anyhow!("parse port range failed: {}", e))
+}
 
-    let p: Vec<&str> = s.split("-").collect();
-    if p.len() != 2 {
-        return Err(err_msg);
+fn cmd_parse_label(raw: &str) -> Result<(String, String)> {
+    match raw.split_once('=') {
+        Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().to_string(), v.trim().to_string())),
+        _ => Err(anyhow!("invalid label {:?}, format: key=value", raw)),
     }
+}
+
+/// Parse `<local>=<remote>[@bind][,option...]`, see the readme for options.
+///
+/// Errors name the part of the spec that is wrong.
+fn parse_link(raw_link: &str, to: Option<&str>) -> Result<Link> {
+    parse_link_spec(raw_link, to).map_err(|e| anyhow!("invalid link {:?}: {:#}", raw_link, e))
+}
 
-    let min = p[0].parse::<u16>();
+fn parse_link_spec(raw_link: &str, to: Option<&str>) -> Result<Link> {
+    let mut link = Link::default();
+    let to = to.map(|t| t.trim_start_matches('[').trim_end_matches(']'));
 
-    let Ok(min) = min else {
-        error!("{}", min.unwrap_err());
-        return Err(err_msg);
+    let mut parts = raw_link.split(',');
+    let spec = parts.next().unwrap_or_default().trim();
+    let (local, remote) = match spec.split_once('=') {
+        Some((local, remote)) => (local.trim(), Some(remote.trim())),
+        None => (spec, None),
     };
+    if remote.is_some_and(|r| r.contains('=')) {
+        bail!("too many '=' in {:?}, options go after a ','", spec);
+    }
 
-    let max = p[1].parse::<u16>();
+    link.local = parse_target(local).map_err(|e| anyhow!("local part {:?}: {}", local, e))?;
 
-    let Ok(max) = max else {
-        error!("{}", max.unwrap_err());
-        return Err(err_msg);
+    link.remote = match remote {
+        // only the local port, server from `to`
+        None => Address {
+            host: to
+                .ok_or_else(|| anyhow!("no server given, write 8080=server.com or set `to`"))?
+                .to_string(),
+            port: 0,
+        },
+        Some(mut remote) => {
+            // optional @bind_ip
+            if let Some((addr, bind)) = remote.rsplit_once('@') {
+                let bind = bind.trim_start_matches('[').trim_end_matches(']');
+                let Ok(bind) = bind.parse::<IpAddr>() else {
+                    bail!("invalid bind address {:?}", bind);
+                };
+                link.bind = Some(bind);
+                remote = addr;
+            }
+            parse_address(remote, to, Some(0))
+                .map_err(|e| anyhow!("server part {:?}: {}", remote, e))?
+        }
     };
 
-    if min > max {
-        return Err(err_msg);
+    for opt in parts {
+        parse_link_option(&mut link, opt.trim())?;
     }
-    Ok(min..=max)
+    Ok(link)
 }
 
-fn cmd_parse_link(raw_link: &str) -> Result<Link> {
-    parse_link(raw_link, None)
+/// apply one `key=value` or flag option of a link spec
+fn parse_link_option(link: &mut Link, opt: &str) -> Result<()> {
+    let (key, value) = match opt.split_once('=') {
+        Some((k, v)) => (k.trim(), Some(v.trim())),
+        None => (opt, None),
+    };
+    let need = || {
+        value
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("option {:?} needs a value", key))
+    };
+    match key {
+        "name" => link.name = Some(need()?.to_string()),
+        "desc" | "description" => link.description = Some(need()?.to_string()),
+        "label" => {
+            let (k, v) = cmd_parse_label(need()?)?;
+            link.labels.insert(k, v);
+        }
+        "proxy_protocol" => {
+            if value.is_some() {
+                bail!("option \"proxy_protocol\" takes no value");
+            }
+            link.proxy_protocol = true;
+        }
+        "allow" => link.allow.push(parse_allow(need()?)?),
+        "max_conns" => {
+            let v = need()?;
+            match v.parse::<u32>() {
+                Ok(n) if n > 0 => link.max_conns = Some(n),
+                _ => bail!("invalid max_conns {:?}, expected a positive number", v),
+            }
+        }
+        "secret" => link.secret = Some(hash_secret(need()?)),
+        "secret_file" => link.secret = Some(hash_secret(read_secret_file(need()?)?)),
+        "control_port" => link.control_port = Some(parse_option(key, need()?)?),
+        "retry" => link.retry = Some(parse_option(key, need()?)?),
+        "retry_interval" => link.retry_interval = Some(parse_option(key, need()?)?),
+        "retry_max_interval" => link.retry_max_interval = Some(parse_option(key, need()?)?),
+        "connect_timeout" => link.connect_timeout = Some(parse_option(key, need()?)?),
+        "start_timeout" => link.start_timeout = Some(parse_option(key, need()?)?),
+        "private" => {
+            if value.is_some() {
+                bail!("option \"private\" takes no value");
+            }
+            link.private = true;
+        }
+        "visitor_secret" => link.visitor_secret = Some(hash_secret(need()?)),
+        "visitor_secret_file" => {
+            link.visitor_secret = Some(hash_secret(read_secret_file(need()?)?))
+        }
+        "forward" => link.forward = Some(parse_forward(need()?)?),
+        "socks_user" => link.socks_user = Some(need()?.to_string()),
+        "socks_pass" => link.socks_pass = Some(need()?.to_string()),
+        "socks_pass_file" => link.socks_pass = Some(read_secret_file(need()?)?),
+        "socks_allow" => link.socks_allow.push(parse_socks_allow(need()?)?),
+        "tls" => {
+            if value.is_some() {
+                bail!("option \"tls\" takes no value");
+            }
+            link.tls = true;
+        }
+        "tls_sni" => link.tls_sni = Some(need()?.to_string()),
+        "tls_ca" => link.tls_ca = Some(need()?.to_string()),
+        "tls_insecure" => {
+            if value.is_some() {
+                bail!("option \"tls_insecure\" takes no value");
+            }
+            link.tls_insecure = true;
+        }
+        "public_tls" => {
+            if value.is_some() {
+                bail!("option \"public_tls\" takes no value");
+            }
+            link.public_tls = true;
+        }
+        "" => bail!("empty option, check for a stray ','"),
+        _ => bail!(
+            "unknown option {:?}, expected one of {}",
+            key,
+            LINK_OPTIONS.join(", ")
+        ),
+    }
+    Ok(())
 }
 
-fn parse_link(raw_link: &str, to: Option<&str>) -> Result<Link> {
-    let err_msg = anyhow!("parse link failed,format: 80=stab.com or localhost:80=stab.com:8989");
-    let mut link = Link::default();
+/// options accepted after a link spec
+const LINK_OPTIONS: &[&str] = &[
+    "name",
+    "desc",
+    "label",
+    "proxy_protocol",
+    "allow",
+    "max_conns",
+    "secret",
+    "secret_file",
+    "control_port",
+    "retry",
+    "retry_interval",
+    "retry_max_interval",
+    "connect_timeout",
+    "start_timeout",
+    "private",
+    "visitor_secret",
+    "visitor_secret_file",
+    "forward",
+    "socks_user",
+    "socks_pass",
+    "socks_pass_file",
+    "socks_allow",
+    "tls",
+    "tls_sni",
+    "tls_ca",
+    "tls_insecure",
+    "public_tls",
+];
+
+/// the local part of a link: `socks5`, `unix:/path` or an address
+fn parse_target(local: &str) -> Result<Target> {
+    if let Some(path) = local.strip_prefix("unix:") {
+        if path.is_empty() {
+            bail!("missing socket path");
+        }
+        #[cfg(unix)]
+        return Ok(Target::Unix(path.into()));
+        #[cfg(not(unix))]
+        bail!("unix sockets are not supported on this platform");
+    }
+    match local {
+        "socks5" => Ok(Target::Socks5),
+        _ => parse_address(local, Some("127.0.0.1"), None).map(Target::Tcp),
+    }
+}
 
-    let addrs: Vec<&str> = raw_link.split("=").collect();
+fn parse_socks_allow(rule: &str) -> Result<DestRule> {
+    rule.parse()
+        .map_err(|e| anyhow!("invalid socks_allow {:?}: {}", rule, e))
+}
 
-    // only port
-    if addrs.len() == 1 && to.is_some() {
-        // parse local address
-        let local_addr = parse_address(addrs[0], Some("127.0.0.1"), None);
+/// the `host:port` a forward link asks the server to dial
+fn parse_forward(addr: &str) -> Result<Address> {
+    parse_address(addr, None, None).map_err(|e| anyhow!("forward {:?}: {}", addr, e))
+}
 
-        let Some(local_addr) = local_addr else {
-            return Err(err_msg);
-        };
+/// parse a numeric link option
+fn parse_option<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid {} {:?}, expected a number", key, value))
+}
 
-        let remote_addr = Address {
-            host: to.unwrap().to_string(),
-            port: 0,
+/// an IP or CIDR network, such as `10.0.0.0/8`
+fn parse_allow(s: &str) -> Result<IpNet> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| {
+            anyhow!(
+                "invalid allow {:?}, expected an IP or CIDR such as 10.0.0.0/8",
+                s
+            )
+        })
+}
+
+fn parse_address(
+    addr: &str,
+    default_host: Option<&str>,
+    default_port: Option<u16>,
+) -> Result<Address> {
+    let parse_port = |p: &str| {
+        p.parse::<u16>()
+            .map_err(|_| anyhow!("invalid port {:?}, expected 0-65535", p))
+    };
+    let missing_port = || anyhow!("missing port");
+    let missing_host = || anyhow!("missing host, give one or set `to`");
+
+    if addr.is_empty() {
+        bail!("empty address");
+    }
+
+    // [v6]:port or [v6]
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| anyhow!("missing ']'"))?;
+        host.parse::<Ipv6Addr>()
+            .map_err(|_| anyhow!("invalid IPv6 address {:?}", host))?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => parse_port(port)?,
+            None if rest.is_empty() => default_port.ok_or_else(missing_port)?,
+            None => bail!("unexpected {:?} after ']'", rest),
         };
-        link.local = local_addr;
-        link.remote = remote_addr;
-        return Ok(link);
+        return Ok(Address {
+            host: host.to_string(),
+            port,
+        });
     }
 
-    if addrs.len() != 2 {
-        return Err(err_msg);
+    // bare v6, host only
+    if addr.parse::<Ipv6Addr>().is_ok() {
+        return Ok(Address {
+            host: addr.to_string(),
+            port: default_port.ok_or_else(missing_port)?,
+        });
     }
-    let local_addr = addrs[0];
-    let remote_addr = addrs[1];
 
-    let local_addr = parse_address(local_addr, Some("127.0.0.1"), None);
+    match addr.split_once(':') {
+        // host:port
+        Some((host, port)) => {
+            if port.contains(':') {
+                bail!("too many ':', write IPv6 addresses as [addr]:port");
+            }
+            let host = match host {
+                "" => default_host.ok_or_else(missing_host)?,
+                host => host,
+            };
+            Ok(Address {
+                host: host.to_string(),
+                port: parse_port(port)?,
+            })
+        }
+        // port only
+        None if addr.bytes().all(|b| b.is_ascii_digit()) => Ok(Address {
+            host: default_host.ok_or_else(missing_host)?.to_string(),
+            port: parse_port(addr)?,
+        }),
+        // host only
+        None => Ok(Address {
+            host: addr.to_string(),
+            port: default_port.ok_or_else(missing_port)?,
+        }),
+    }
+}
 
-    let Some(local_addr) = local_addr else {
-        return Err(err_msg);
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // pares remote address
-    let remote_addr = parse_address(remote_addr, to, Some(0));
+    fn addr(host: &str, port: u16) -> Address {
+        Address {
+            host: host.to_string(),
+            port,
+        }
+    }
 
-    let Some(remote_addr) = remote_addr else {
-        return Err(err_msg);
-    };
+    fn err(spec: &str, to: Option<&str>) -> String {
+        format!("{:#}", parse_link(spec, to).unwrap_err())
+    }
 
-    link.local = local_addr;
-    link.remote = remote_addr;
-    return Ok(link);
-}
+    #[test]
+    fn link_spec_forms() {
+        let link = parse_link("8000=server.com:9000", None).unwrap();
+        assert_eq!(link.local, Target::Tcp(addr("127.0.0.1", 8000)));
+        assert_eq!(link.remote, addr("server.com", 9000));
 
-fn parse_address(
-    addr: &str,
-    default_host: Option<&str>,
-    default_port: Option<u16>,
-) -> Option<Address> {
-    let addr: Vec<&str> = addr.split(":").collect();
+        let link = parse_link(" 10.0.0.2:22 = server.com ", None).unwrap();
+        assert_eq!(link.local, Target::Tcp(addr("10.0.0.2", 22)));
+        assert_eq!(link.remote, addr("server.com", 0));
+
+        let link = parse_link("8000", Some("[2001:db8::1]")).unwrap();
+        assert_eq!(link.remote, addr("2001:db8::1", 0));
 
-    if addr.len() > 2 {
-        return None;
+        let link = parse_link("[::1]:8000=[2001:db8::1]:7878@[2001:db8::5]", None).unwrap();
+        assert_eq!(link.local, Target::Tcp(addr("::1", 8000)));
+        assert_eq!(link.remote, addr("2001:db8::1", 7878));
+        assert_eq!(link.bind, Some("2001:db8::5".parse().unwrap()));
+
+        // a bare IPv6 address is a host, not host:port
+        let link = parse_link("8000=2001:db8::1:80", None).unwrap();
+        assert_eq!(link.remote, addr("2001:db8::1:80", 0));
+
+        let link = parse_link("8000=:9000", Some("server.com")).unwrap();
+        assert_eq!(link.remote, addr("server.com", 9000));
     }
-    let mut address = Address::default();
 
-    // host or port
-    if addr.len() == 1 {
-        let port = addr[0].parse::<u16>();
-        if let Ok(port) = port {
-            address.host = default_host?.to_string();
-            address.port = port;
-        } else {
-            let host = addr[0].to_string();
-            address.host = host;
-            address.port = default_port?;
+    #[test]
+    fn link_options() {
+        let spec = "8000=server.com:7878, name=web,desc=a b,label=env=staging,\
+                    proxy_protocol,allow=10.0.0.0/8,allow=192.168.1.5,max_conns=20";
+        let link = parse_link(spec, None).unwrap();
+        assert_eq!(link.name.as_deref(), Some("web"));
+        assert_eq!(link.description.as_deref(), Some("a b"));
+        assert_eq!(link.labels["env"], "staging");
+        assert!(link.proxy_protocol);
+        let allow: Vec<IpNet> = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "192.168.1.5/32".parse().unwrap(),
+        ];
+        assert_eq!(link.allow, allow);
+        assert_eq!(link.max_conns, Some(20));
+    }
+
+    #[test]
+    fn reserved_from_env() {
+        let r = parse_reserved("ports=9000-9010,9020  client=alice-laptop").unwrap();
+        assert_eq!(r.ports.to_string(), "9000-9010,9020");
+        assert_eq!(r.client.as_deref(), Some("alice-laptop"));
+        assert_eq!(r.secret, None);
+
+        let r = parse_reserved("client=bob secret=s3cret ports=9100").unwrap();
+        assert_eq!(r.secret, Some(hash_secret("s3cret")));
+
+        assert!(parse_reserved("ports=9100").is_err());
+        assert!(parse_reserved("ports=9100 owner=bob").is_err());
+        assert!(parse_reserved("9100 client=bob").is_err());
+        assert!(parse_reserved("client=bob").is_err());
+    }
+
+    #[test]
+    fn link_spec_errors() {
+        assert!(err("8000=server.com,", None).contains("stray ','"));
+        assert!(err("8000=server.com,,name=a", None).contains("stray ','"));
+        assert!(err("8000=server.com=9000", None).contains("too many '='"));
+        assert!(err("8000=server.com,name=", None).contains("needs a value"));
+        assert!(err("8000=server.com,name", None).contains("needs a value"));
+        assert!(err("8000=server.com,proxy_protocol=1", None).contains("takes no value"));
+        assert!(err("8000=server.com,max_conns=0", None).contains("max_conns"));
+        assert!(err("8000=server.com,nmae=web", None).contains("unknown option"));
+        assert!(err("8000=server.com,allow=10.0.0.0/33", None).contains("invalid allow"));
+        assert!(err("8000", None).contains("no server given"));
+        assert!(err("=server.com", None).contains("local part"));
+        assert!(err("8000=server.com:70000", None).contains("invalid port"));
+        assert!(err("8000=server.com:80:81", None).contains("too many ':'"));
+        assert!(err("8000=server.com@nope", None).contains("invalid bind address"));
+    }
+
+    #[test]
+    fn link_debug_hides_secrets() {
+        let spec = "8000=server.com,secret=s3cret,visitor_secret=v1sit,socks_pass=SUPERSECRETPW";
+        let link = parse_link(spec, None).unwrap();
+        let debug = format!("{:?}", link);
+        for secret in [
+            "s3cret",
+            "v1sit",
+            "SUPERSECRETPW",
+            link.secret.as_deref().unwrap(),
+            link.visitor_secret.as_deref().unwrap(),
+        ] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
         }
-        return Some(address);
+        assert!(debug.contains("server.com"));
     }
 
-    // host:port
-    let port = addr[1].parse::<u16>();
-    let Ok(port) = port else {
-        error!("{}", port.unwrap_err());
-        return None;
-    };
-    address.host = addr[0].to_string();
-    address.port = port;
-    return Some(address);
+    #[test]
+    fn unknown_keys_are_located() {
+        let src = "port = 1\nport_rang = \"1-2\"\n[server]\nreconect_grace = 3\npool_size = 1\n\
+                   [local]\nretry = 1\nretyr = 3\n[local.servers.a]\nhost = \"x\"\n";
+        let unknown: Vec<_> = unknown_keys(src)
+            .into_iter()
+            .map(|(offset, e)| (line_col(src, offset), e.to_string()))
+            .collect();
+        assert_eq!(
+            unknown,
+            [
+                ((2, 1), "unknown key \"port_rang\"".to_string()),
+                ((8, 1), "[local] unknown key \"retyr\"".to_string()),
+                (
+                    (4, 1),
+                    "[server] unknown key \"reconect_grace\"".to_string()
+                ),
+            ]
+        );
+        assert!(field_names::<ServerConfig>().contains(&"tls_key"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn file_paths_are_relative_to_the_config_file() {
+        let id = |path: &str| beside("/etc/stab/stab.toml", path.to_string());
+        assert_eq!(id("stab.id"), "/etc/stab/stab.id");
+        assert_eq!(id("state/stab.id"), "/etc/stab/state/stab.id");
+        assert_eq!(id("/var/lib/stab/stab.id"), "/var/lib/stab/stab.id");
+
+        let cwd = std::env::current_dir().unwrap();
+        let id = beside("conf/stab.toml", "stab.id".to_string());
+        assert_eq!(Path::new(&id), cwd.join("conf/stab.id"));
+    }
 }
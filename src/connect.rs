@@ -0,0 +1,48 @@
+//! `stab connect`: one visit over stdin/stdout, e.g. as ssh's `ProxyCommand`
+
+use anyhow::Result;
+use tokio::io::{copy_bidirectional, join, stdin, stdout, AsyncWriteExt};
+
+use crate::{
+    config::{build_config, print_errors, Link, StabArgs, StabConfig},
+    local::{open_visit, LinkExit, EXIT_CONFIG},
+    share::ErrorInfo,
+};
+
+/// Pipe stdin/stdout through the private tunnel `tunnel` on `server`; returns
+/// the exit code.
+///
+/// `tunnel` is the tunnel name, optionally followed by link options such as
+/// `ssh,visitor_secret=xyz`. Nothing is logged, errors go to stderr.
+pub async fn connect(mut args: StabArgs, server: &str, tunnel: &str) -> i32 {
+    // the command line links come last, so this one is `cfg.links.last()`
+    args.links = vec![format!("0={},name={}", server, tunnel)];
+    let cfg = match build_config(&args) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            print_errors(&e);
+            return EXIT_CONFIG;
+        }
+    };
+    let link = cfg.links.last().expect("link from the command line");
+    match pipe(&cfg, link).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            match e.downcast_ref::<ErrorInfo>() {
+                Some(e) => LinkExit::Rejected(e.kind).code(),
+                None => 1,
+            }
+        }
+    }
+}
+
+async fn pipe(cfg: &StabConfig, link: &Link) -> Result<()> {
+    let (mut tunnel, head) = open_visit(link, &link.settings(cfg)).await?;
+    let mut stdout = stdout();
+    stdout.write_all(&head).await?;
+    stdout.flush().await?;
+    let mut stdio = join(stdin(), stdout);
+    copy_bidirectional(&mut stdio, &mut tunnel).await?;
+    Ok(())
+}
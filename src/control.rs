@@ -2,6 +2,7 @@
 
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     sync::{Arc, Mutex},
 };
 
@@ -10,7 +11,13 @@ use crate::server::CtlConInfo;
 /// Concurrent map for control connection info.
 #[derive(Debug)]
 pub struct CtlConns {
-    map: Mutex<HashMap<u16, Arc<CtlConInfo>>>,
+    map: Mutex<HashMap<SocketAddr, Arc<CtlConInfo>>>,
+}
+
+impl Default for CtlConns {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CtlConns {
@@ -22,27 +29,33 @@ impl CtlConns {
     }
 
     /// get value by key
-    pub fn get(&self, port: u16) -> Option<Arc<CtlConInfo>> {
+    pub fn get(&self, addr: SocketAddr) -> Option<Arc<CtlConInfo>> {
         let map = self.map.lock().unwrap();
-        map.get(&port).cloned()
+        map.get(&addr).cloned()
     }
 
     /// insert new value
-    pub fn insert(&self, port: u16, ctl: CtlConInfo) -> Option<Arc<CtlConInfo>> {
+    pub fn insert(&self, addr: SocketAddr, ctl: CtlConInfo) -> Option<Arc<CtlConInfo>> {
         let mut map = self.map.lock().unwrap();
-        map.insert(port, Arc::new(ctl))
+        map.insert(addr, Arc::new(ctl))
     }
 
     /// remove key
-    pub fn remove(&self, port: u16) {
+    pub fn remove(&self, addr: SocketAddr) {
+        let mut map = self.map.lock().unwrap();
+        let _ = map.remove(&addr);
+    }
+
+    /// remove every entry `f` matches
+    pub fn remove_if(&self, f: impl Fn(&CtlConInfo) -> bool) {
         let mut map = self.map.lock().unwrap();
-        let _ = map.remove(&port);
+        map.retain(|_, info| !f(info));
     }
 
     /// add traffic counters
-    pub fn add_data(&self, port: u16, up_stream: u64, down_stream: u64) {
+    pub fn add_data(&self, addr: SocketAddr, up_stream: u64, down_stream: u64) {
         let mut map = self.map.lock().unwrap();
-        if let Some(data) = map.get_mut(&port) {
+        if let Some(data) = map.get_mut(&addr) {
             let info = Arc::make_mut(data);
             info.upstream += up_stream;
             info.downstream += down_stream;
@@ -50,10 +63,10 @@ impl CtlConns {
         }
     }
 
-    /// whether the port exists
-    pub fn contain(&self, port: u16) -> bool {
+    /// whether the tunnel exists
+    pub fn contain(&self, addr: SocketAddr) -> bool {
         let map = self.map.lock().unwrap();
-        map.contains_key(&port)
+        map.contains_key(&addr)
     }
 
     /// snapshot of all control connections
@@ -0,0 +1,227 @@
+//! destination allowlists such as `10.0.0.0/8:5432` or `db.internal:*`
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::IpNet;
+
+use crate::{
+    ports::PortSet,
+    share::{ErrorInfo, ErrorKind},
+};
+
+/// hosts a rule matches
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Hosts {
+    /// `*`
+    Any,
+    /// an address or network, matched against the resolved address
+    Net(IpNet),
+    /// a host name, matched against the name asked for
+    Name(String),
+}
+
+/// One allowed destination: a host, network or `*`, then `:` and a port set
+/// or `*`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DestRule {
+    hosts: Hosts,
+    /// None allows any port
+    ports: Option<PortSet>,
+}
+
+impl DestRule {
+    /// whether the rule lets `host` be reached at `addr`
+    fn allows(&self, host: &str, addr: SocketAddr) -> bool {
+        if self
+            .ports
+            .as_ref()
+            .is_some_and(|p| !p.contains(addr.port()))
+        {
+            return false;
+        }
+        match &self.hosts {
+            Hosts::Any => true,
+            Hosts::Net(net) => net.contains(&addr.ip().to_canonical()),
+            Hosts::Name(name) => name.eq_ignore_ascii_case(host),
+        }
+    }
+}
+
+impl FromStr for DestRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (host, ports) = match s.strip_prefix('[') {
+            // [fd00::/8]:5432
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(|| anyhow!("missing ']'"))?;
+                let ports = rest
+                    .strip_prefix(':')
+                    .ok_or_else(|| anyhow!("missing port"))?;
+                (host, ports)
+            }
+            None => match s.rsplit_once(':').ok_or_else(|| anyhow!("missing port"))? {
+                // fd00::1:5432 could be either an address or address:port
+                (host, _) if host.contains(':') => {
+                    bail!("write IPv6 hosts in brackets, such as [fd00::1]:5432")
+                }
+                split => split,
+            },
+        };
+        let hosts = match host {
+            "*" => Hosts::Any,
+            "" => bail!("missing host"),
+            h => match (h.parse::<IpNet>(), h.parse::<IpAddr>()) {
+                (Ok(net), _) => Hosts::Net(net),
+                (_, Ok(ip)) => Hosts::Net(ip.into()),
+                _ if h.contains(['/', ' ']) => bail!("invalid host {:?}", h),
+                _ => Hosts::Name(h.to_ascii_lowercase()),
+            },
+        };
+        let ports = match ports {
+            "*" => None,
+            p => Some(p.parse::<PortSet>()?),
+        };
+        Ok(Self { hosts, ports })
+    }
+}
+
+impl fmt::Display for DestRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hosts {
+            Hosts::Any => f.write_str("*")?,
+            // a single address prints as it was given
+            Hosts::Net(net) if net.prefix_len() == net.max_prefix_len() => match net.addr() {
+                IpAddr::V6(ip) => write!(f, "[{}]", ip)?,
+                ip => write!(f, "{}", ip)?,
+            },
+            Hosts::Net(net) if net.addr().is_ipv6() => write!(f, "[{}]", net)?,
+            Hosts::Net(net) => write!(f, "{}", net)?,
+            Hosts::Name(name) => f.write_str(name)?,
+        }
+        match &self.ports {
+            Some(ports) => write!(f, ":{}", ports),
+            None => f.write_str(":*"),
+        }
+    }
+}
+
+/// Resolve `host:port` and return the first address `rules` allow.
+///
+/// Networks are checked against the resolved addresses, so a name cannot
+/// point past them; no rules allow nothing.
+pub async fn resolve(rules: &[DestRule], host: &str, port: u16) -> Result<SocketAddr, ErrorInfo> {
+    let not_allowed =
+        || ErrorInfo::with_details(ErrorKind::NotAllowed, format!("{}:{}", host, port));
+    if rules.is_empty() {
+        return Err(not_allowed());
+    }
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| ErrorInfo::with_details(ErrorKind::Unreachable, format!("{}: {}", host, e)))?;
+    let mut addrs = addrs.peekable();
+    if addrs.peek().is_none() {
+        let details = format!("{}: no address", host);
+        return Err(ErrorInfo::with_details(ErrorKind::Unreachable, details));
+    }
+    addrs
+        .find(|addr| rules.iter().any(|r| r.allows(host, *addr)))
+        .ok_or_else(not_allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> DestRule {
+        s.parse().unwrap()
+    }
+
+    fn sock(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_prints() {
+        for s in [
+            "10.0.0.0/8:5432",
+            "192.168.1.5:22",
+            "[fd00::/8]:5432",
+            "[::1]:80-90,443",
+            "db.internal:*",
+            "*:*",
+            "*:443",
+        ] {
+            assert_eq!(rule(s).to_string(), s);
+        }
+        assert_eq!(rule(" DB.Internal:5432 ").to_string(), "db.internal:5432");
+        assert_eq!(rule("10.1.2.3/32:1").to_string(), "10.1.2.3:1");
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for s in [
+            "",
+            "db.internal",
+            ":5432",
+            "[fd00::/8]5432",
+            "[fd00::/8:5432",
+            "fd00::1:5432:*",
+            "10.0.0.0/33:1",
+            "a b:1",
+            "host:70000",
+            "host:2-1",
+        ] {
+            assert!(s.parse::<DestRule>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn matches_networks_and_ports() {
+        let r = rule("10.0.0.0/8:5432");
+        assert!(r.allows("db", sock("10.1.2.3:5432")));
+        assert!(!r.allows("db", sock("10.1.2.3:5433")));
+        assert!(!r.allows("db", sock("11.0.0.1:5432")));
+
+        let r = rule("[fd00::/8]:*");
+        assert!(r.allows("x", sock("[fd12::1]:1")));
+        assert!(!r.allows("x", sock("[fe80::1]:1")));
+
+        assert!(rule("*:*").allows("any", sock("[::1]:65535")));
+        assert!(!rule("*:443").allows("any", sock("1.1.1.1:80")));
+    }
+
+    #[test]
+    fn mapped_v4_matches_v4_rules() {
+        let r = rule("10.0.0.0/8:*");
+        assert!(r.allows("x", sock("[::ffff:10.0.0.1]:80")));
+        assert!(!r.allows("x", sock("[::ffff:11.0.0.1]:80")));
+    }
+
+    #[test]
+    fn names_match_the_name_asked_for() {
+        let r = rule("db.internal:5432");
+        assert!(r.allows("DB.internal", sock("10.0.0.1:5432")));
+        assert!(!r.allows("10.0.0.1", sock("10.0.0.1:5432")));
+        assert!(!r.allows("other", sock("10.0.0.1:5432")));
+    }
+
+    #[tokio::test]
+    async fn resolve_checks_the_resolved_address() {
+        let rules = [rule("127.0.0.0/8:*")];
+        assert_eq!(
+            resolve(&rules, "127.0.0.1", 80).await.unwrap(),
+            sock("127.0.0.1:80")
+        );
+        let e = resolve(&rules, "10.0.0.1", 80).await.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::NotAllowed);
+        let e = resolve(&[], "127.0.0.1", 80).await.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::NotAllowed);
+    }
+}
@@ -0,0 +1,74 @@
+//! reconnect grace period
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+/// What a parked port hands back when its owner reconnects: the still-bound
+/// listener and the public clients accepted in the meantime.
+pub type Reclaimed = (TcpListener, Vec<TcpStream>);
+
+/// Ask a parked port to give up its listener.
+pub type Handoff = oneshot::Sender<oneshot::Sender<Reclaimed>>;
+
+struct Parked {
+    owner: String,
+    handoff: Handoff,
+}
+
+/// Ports whose control connection dropped and that wait for the same client
+/// identity to come back.
+pub struct ParkedPorts {
+    map: Mutex<HashMap<SocketAddr, Parked>>,
+}
+
+impl std::fmt::Debug for ParkedPorts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let map = self.map.lock().unwrap();
+        f.debug_struct("ParkedPorts")
+            .field("ports", &map.len())
+            .finish()
+    }
+}
+
+impl Default for ParkedPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParkedPorts {
+    /// create new ParkedPorts
+    pub fn new() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// park `addr` for `owner`
+    pub fn insert(&self, addr: SocketAddr, owner: String, handoff: Handoff) {
+        let mut map = self.map.lock().unwrap();
+        map.insert(addr, Parked { owner, handoff });
+    }
+
+    /// Take the parked port of `owner`, if any.
+    ///
+    /// `port == 0` matches any port; otherwise only that exact port.
+    pub fn take(&self, owner: &str, port: u16) -> Option<(SocketAddr, Handoff)> {
+        let mut map = self.map.lock().unwrap();
+        let parked = *map
+            .iter()
+            .find(|(a, v)| v.owner == owner && (port == 0 || a.port() == port))?
+            .0;
+        map.remove(&parked).map(|v| (parked, v.handoff))
+    }
+
+    /// remove key
+    pub fn remove(&self, addr: SocketAddr) {
+        let mut map = self.map.lock().unwrap();
+        let _ = map.remove(&addr);
+    }
+}
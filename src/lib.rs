@@ -0,0 +1,32 @@
+//! stab makes TCP tunnels to localhost.
+//!
+//! The `stab` binary is a thin wrapper over this crate. Embed a server with
+//! [`Server`] or a client with [`Client`]; each runs on its own state, so
+//! several can share one process.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+pub mod check;
+pub mod config;
+pub mod connect;
+pub mod control;
+pub mod dest;
+pub mod grace;
+pub mod listener;
+pub mod local;
+pub mod ports;
+pub mod private;
+pub mod server;
+pub mod share;
+pub mod shutdown;
+pub mod socks;
+pub mod sticky;
+pub mod tcp_pool;
+pub mod tls;
+pub mod web;
+
+pub use config::{Address, CfgHandle, Link, StabConfig, Target};
+pub use listener::{TunnelListener, TunnelStream};
+pub use local::{Client, ClientEvent, ClientHandle, LinkExit};
+pub use server::{Server, ServerEvent, ServerHandle};
@@ -0,0 +1,235 @@
+//! tunnels served in-process instead of dialing a local port
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use futures::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tracing::error;
+
+use crate::{
+    config::{Address, Link, StabConfig},
+    local::{Client, ClientEvent, LinkExit},
+    shutdown::{SessionGuard, Shutdown},
+};
+
+/// tunneled connections queued before the link stops taking new ones
+const ACCEPT_BACKLOG: usize = 128;
+
+/// A tunnel whose connections are handed to this program, like a
+/// [`TcpListener`](tokio::net::TcpListener) bound on the server.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let cfg = stab::StabConfig::default();
+/// let mut listener = stab::TunnelListener::bind(cfg, "0=server.com:9000".parse()?).await?;
+/// println!("serving on {}", listener.remote());
+/// loop {
+///     let stream = listener.accept().await?;
+///     // read from and write to `stream`
+/// }
+/// # }
+/// ```
+///
+/// It implements axum's `Listener`, so `axum::serve(listener, app)` works as
+/// well. Dropping the listener closes the tunnel.
+#[derive(Debug)]
+pub struct TunnelListener {
+    remote: Address,
+    incoming: mpsc::Receiver<TunnelStream>,
+    shutdown: Arc<Shutdown>,
+    events: broadcast::Sender<ClientEvent>,
+    task: Option<JoinHandle<LinkExit>>,
+    exit: Option<LinkExit>,
+}
+
+impl TunnelListener {
+    /// Open `link` with the server settings of `cfg`; returns once the server
+    /// accepted it.
+    ///
+    /// `link.local` is never dialed, it only names the tunnel to the server
+    /// (dashboard, sticky ports). The links of `cfg` are ignored.
+    pub async fn bind(mut cfg: StabConfig, link: Link) -> Result<Self> {
+        cfg.links = vec![Arc::new(link)];
+        let (sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let client = Client::new(cfg).deliver_to(sender);
+        let mut events = client.subscribe();
+        let handle = client.start().await?;
+        let (state, task) = handle.into_parts();
+        let shutdown = state.shutdown.clone();
+        let events_sender = state.events.clone();
+        drop(state);
+
+        let remote = loop {
+            match events.recv().await {
+                Ok(ClientEvent::Connected { remote, .. }) => break remote,
+                Ok(ClientEvent::Stopped { exit, .. }) => bail!("tunnel not opened: {:?}", exit),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => bail!("tunnel not opened"),
+            }
+        };
+        Ok(Self {
+            remote,
+            incoming,
+            shutdown,
+            events: events_sender,
+            task: Some(task),
+            exit: None,
+        })
+    }
+
+    /// public address of the tunnel, host as configured for the server
+    ///
+    /// This is the address from [`TunnelListener::bind`]; a link that asked
+    /// for any port may get another one if it has to reconnect.
+    pub fn remote(&self) -> &Address {
+        &self.remote
+    }
+
+    /// Wait for the next tunneled connection.
+    ///
+    /// Fails once the link stopped for good, e.g. after its retries ran out.
+    pub async fn accept(&mut self) -> io::Result<TunnelStream> {
+        if let Some(task) = &mut self.task {
+            tokio::select! {
+                Some(stream) = self.incoming.recv() => return Ok(stream),
+                exit = task => {
+                    self.exit = Some(exit.unwrap_or(LinkExit::Closed));
+                    self.task = None;
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("tunnel closed: {:?}", self.exit.unwrap_or(LinkExit::Closed)),
+        ))
+    }
+
+    /// the accepted connections as a stream, ending when the link stops
+    pub fn incoming(self) -> impl Stream<Item = io::Result<TunnelStream>> {
+        futures::stream::unfold(self, |mut listener| async move {
+            match listener.accept().await {
+                Ok(stream) => Some((Ok(stream), listener)),
+                Err(_) => None,
+            }
+        })
+    }
+
+    /// receive [`ClientEvent`]s of the link, e.g. reconnects
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// close the tunnel; accepted streams keep working
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+}
+
+impl Drop for TunnelListener {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+impl axum::serve::Listener for TunnelListener {
+    type Io = TunnelStream;
+    type Addr = Address;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match TunnelListener::accept(self).await {
+            Ok(stream) => (stream, self.remote.clone()),
+            Err(e) => {
+                // axum keeps accepting forever; idle until it shuts down
+                error!("{}", e);
+                std::future::pending().await
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.remote.clone())
+    }
+}
+
+/// One connection through a [`TunnelListener`].
+///
+/// The peer is not known to the local; with the link's `proxy_protocol` set
+/// the stream starts with a PROXY protocol v1 header naming it.
+#[derive(Debug)]
+pub struct TunnelStream {
+    stream: TcpStream,
+    /// bytes the server sent along with `Start`, read before `stream`
+    head: Vec<u8>,
+    pos: usize,
+    tunnel: SocketAddr,
+    _session: SessionGuard,
+}
+
+impl TunnelStream {
+    pub(crate) fn new(
+        stream: TcpStream,
+        head: Vec<u8>,
+        tunnel: SocketAddr,
+        session: SessionGuard,
+    ) -> Self {
+        Self {
+            stream,
+            head,
+            pos: 0,
+            tunnel,
+            _session: session,
+        }
+    }
+
+    /// the server-side address the connection came in on
+    pub fn tunnel_addr(&self) -> SocketAddr {
+        self.tunnel
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.head.len() {
+            let n = buf.remaining().min(this.head.len() - this.pos);
+            buf.put_slice(&this.head[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -1,67 +1,474 @@
 //! the local module code
 
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use rand::Rng;
+use serde_json::json;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    net::TcpStream,
-    task::JoinHandle,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+    task::{AbortHandle, JoinHandle, JoinSet},
     time::{sleep, timeout},
 };
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, trace, trace_span, warn, Instrument};
 use uuid::Uuid;
 
 use crate::{
-    config::{Link, G_CFG},
-    share::{proxy, proxy_with_prepend, FrameStream, Msg, NETWORK_TIMEOUT},
+    config::{Address, CfgHandle, ConfigSource, Link, LinkSettings, Mode, StabConfig, Target},
+    listener::TunnelStream,
+    share::{
+        proxy, proxy_with_prepend, ClientInfo, ErrorInfo, ErrorKind, FrameStream, LinkInfo, Msg,
+        NETWORK_TIMEOUT, PROTOCOL_VERSION,
+    },
+    shutdown::Shutdown,
+    socks, tls,
 };
 
-/// run local
-pub async fn run() {
-    let links = &G_CFG.get().unwrap().links;
-    let port = G_CFG.get().unwrap().port;
-    let mut joins: Vec<JoinHandle<()>> = Vec::new();
+/// Process exit code when a link gave up after exhausting its reconnect attempts.
+pub const EXIT_RETRY_EXHAUSTED: i32 = 75;
+
+/// Process exit code when the server reported a protocol mismatch.
+pub const EXIT_PROTOCOL: i32 = 76;
+
+/// Process exit code when the server refused our credentials or banned us.
+pub const EXIT_NO_PERMISSION: i32 = 77;
+
+/// Process exit code when the link config cannot be satisfied (e.g. port out of range).
+pub const EXIT_CONFIG: i32 = 78;
+
+/// Why a link stopped reconnecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkExit {
+    /// closed and `retry = 0`
+    Closed,
+    /// reconnect attempts used up
+    Exhausted,
+    /// the server refused the link with a non-retryable error
+    Rejected(ErrorKind),
+}
+
+impl LinkExit {
+    /// process exit code for this outcome
+    pub fn code(self) -> i32 {
+        match self {
+            LinkExit::Closed => 0,
+            LinkExit::Exhausted => EXIT_RETRY_EXHAUSTED,
+            LinkExit::Rejected(ErrorKind::Auth | ErrorKind::Banned) => EXIT_NO_PERMISSION,
+            LinkExit::Rejected(ErrorKind::ProtocolMismatch) => EXIT_PROTOCOL,
+            LinkExit::Rejected(_) => EXIT_CONFIG,
+        }
+    }
+
+    /// Rejections outrank exhaustion, which outranks a clean close.
+    fn rank(self) -> u8 {
+        match self {
+            LinkExit::Closed => 0,
+            LinkExit::Exhausted => 1,
+            LinkExit::Rejected(_) => 2,
+        }
+    }
+}
+
+/// events buffered per subscriber before the slowest one misses some
+const EVENT_CAPACITY: usize = 256;
+
+/// What happens on a running client, see [`ClientHandle::subscribe`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ClientEvent {
+    /// the server accepted a link
+    Connected {
+        /// the link
+        link: Arc<Link>,
+        /// public address of the tunnel, host as configured for the server
+        remote: Address,
+    },
+    /// a link dropped and will reconnect unless its retries are used up
+    Disconnected {
+        /// the link
+        link: Arc<Link>,
+        /// why, if it failed
+        error: Option<String>,
+    },
+    /// the server answered a link with an error; the link retries unless
+    /// the kind says a retry cannot help
+    Refused {
+        /// the link
+        link: Arc<Link>,
+        /// what the server said
+        error: ErrorInfo,
+    },
+    /// a link stopped for good
+    Stopped {
+        /// the link
+        link: Arc<Link>,
+        /// why it stopped
+        exit: LinkExit,
+    },
+}
+
+impl ClientEvent {
+    /// The event as one JSON object, for `--json`: `event` names it,
+    /// `link` is `local -> remote`, and server errors carry their `kind`
+    /// (`"port_in_use"`, ...) and `details`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let name = |link: &Link| format!("{} -> {}", link.local, link.remote);
+        match self {
+            ClientEvent::Connected { link, remote } => json!({
+                "event": "connected",
+                "link": name(link),
+                "remote": remote.to_string(),
+            }),
+            ClientEvent::Disconnected { link, error } => json!({
+                "event": "disconnected",
+                "link": name(link),
+                "error": error,
+            }),
+            ClientEvent::Refused { link, error } => json!({
+                "event": "refused",
+                "link": name(link),
+                "kind": error.kind,
+                "details": error.details,
+                "retryable": error.kind.is_retryable(),
+            }),
+            ClientEvent::Stopped { link, exit } => {
+                let kind = match exit {
+                    LinkExit::Rejected(kind) => Some(*kind),
+                    _ => None,
+                };
+                json!({
+                    "event": "stopped",
+                    "link": name(link),
+                    "kind": kind,
+                    "exit_code": exit.code(),
+                })
+            }
+        }
+    }
+}
+
+/// State of one running client, shared by its link tasks.
+#[derive(Debug)]
+pub(crate) struct ClientState {
+    /// the client's config
+    cfg: Arc<CfgHandle>,
+    /// shutdown signal and session count
+    pub(crate) shutdown: Arc<Shutdown>,
+    /// persistent id of this local, sent so the server can hand back the same ports
+    client_id: String,
+    /// build and host details reported to the server
+    client_info: ClientInfo,
+    /// event fan-out
+    pub(crate) events: broadcast::Sender<ClientEvent>,
+    /// where tunneled connections go instead of dialing `link.local`
+    incoming: Option<mpsc::Sender<TunnelStream>>,
+}
+
+impl ClientState {
+    fn emit(&self, event: ClientEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+}
+
+/// Builder of a stab client (local mode).
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let mut cfg = stab::StabConfig::default();
+/// cfg.links.push(std::sync::Arc::new("8000=server.com".parse()?));
+/// let client = stab::Client::new(cfg);
+/// let mut events = client.subscribe();
+/// let client = client.start().await?;
+/// let exit = client.wait().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client {
+    cfg: StabConfig,
+    source: Option<ConfigSource>,
+    events: broadcast::Sender<ClientEvent>,
+    incoming: Option<mpsc::Sender<TunnelStream>>,
+}
+
+impl Client {
+    /// a client running the links of `cfg`, as a visitor in visitor mode
+    pub fn new(mut cfg: StabConfig) -> Self {
+        if cfg.mode == Mode::Server {
+            cfg.mode = Mode::Local;
+        }
+        Self {
+            cfg,
+            source: None,
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            incoming: None,
+        }
+    }
+
+    /// receive [`ClientEvent`]s, including those right after the start
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// rebuild the config from `source` on [`CfgHandle::reload`]
+    pub fn reload_from(mut self, source: ConfigSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// hand tunneled connections to `sender` instead of dialing the links' local side
+    pub(crate) fn deliver_to(mut self, sender: mpsc::Sender<TunnelStream>) -> Self {
+        self.incoming = Some(sender);
+        self
+    }
+
+    /// Start every link in the background.
+    pub async fn start(self) -> Result<ClientHandle> {
+        let client_id = load_client_id(&self.cfg.client_id_file);
+        let started = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let state = Arc::new(ClientState {
+            cfg: Arc::new(CfgHandle::new(self.cfg, self.source)),
+            shutdown: Arc::new(Shutdown::new()),
+            client_id,
+            client_info: ClientInfo::current(&started),
+            events: self.events,
+            incoming: self.incoming,
+        });
+        let task = tokio::spawn(run(state.clone()));
+        Ok(ClientHandle { state, task })
+    }
+}
+
+/// A running client started by [`Client::start`].
+#[derive(Debug)]
+pub struct ClientHandle {
+    state: Arc<ClientState>,
+    task: JoinHandle<LinkExit>,
+}
+
+impl ClientHandle {
+    /// the client's config; updating it starts and stops links to match
+    pub fn config(&self) -> &Arc<CfgHandle> {
+        &self.state.cfg
+    }
+
+    /// the shutdown signal, e.g. for a signal handler
+    pub fn shutdown_signal(&self) -> Arc<Shutdown> {
+        self.state.shutdown.clone()
+    }
+
+    /// close every link; [`ClientHandle::wait`] returns once drained
+    pub fn shutdown(&self) {
+        self.state.shutdown.trigger();
+    }
+
+    /// receive [`ClientEvent`]s from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// the state and run task, for wrappers that manage the client themselves
+    pub(crate) fn into_parts(self) -> (Arc<ClientState>, JoinHandle<LinkExit>) {
+        (self.state, self.task)
+    }
+
+    /// Wait until every link stopped; returns the most severe reason.
+    pub async fn wait(self) -> LinkExit {
+        match self.task.await {
+            Ok(exit) => exit,
+            Err(e) => {
+                error!("client task failed: {}", e);
+                LinkExit::Closed
+            }
+        }
+    }
+}
+
+/// run local until every link stopped
+///
+/// On config reload, links that disappeared are stopped and new ones started;
+/// links present in both configs keep running. On shutdown every link closes
+/// cleanly and active sessions get `shutdown_timeout` to finish.
+async fn run(state: Arc<ClientState>) -> LinkExit {
+    let mut reloaded = state.cfg.subscribe();
+    let mut joins: JoinSet<LinkExit> = JoinSet::new();
+    let mut running: HashMap<Link, (CancellationToken, AbortHandle)> = HashMap::new();
+    start_links(&state, &mut joins, &mut running);
+
+    let mut exit = LinkExit::Closed;
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            _ = state.shutdown.triggered(), if !stopping => stopping = true,
+            Ok(()) = reloaded.changed(), if !stopping => {
+                start_links(&state, &mut joins, &mut running)
+            }
+            join = joins.join_next() => match join {
+                None => break,
+                Some(Ok(e)) if e.rank() > exit.rank() => exit = e,
+                Some(Ok(_)) => {}
+                Some(Err(e)) if e.is_cancelled() => {}
+                Some(Err(e)) => error!("{}", e),
+            },
+        }
+    }
+
+    if stopping {
+        let deadline = Duration::from_secs(state.cfg.get().shutdown_timeout);
+        let left = state.shutdown.drain(deadline).await;
+        if left > 0 {
+            warn!("{} sessions cut off by shutdown", left);
+        }
+    }
+    exit
+}
+
+/// Bring the running links in line with the current config.
+///
+/// A link dropped from the config is stopped like on shutdown, so it tells
+/// the server to free its port; it is aborted if that takes too long.
+fn start_links(
+    state: &Arc<ClientState>,
+    joins: &mut JoinSet<LinkExit>,
+    running: &mut HashMap<Link, (CancellationToken, AbortHandle)>,
+) {
+    let links = state.cfg.get().links.clone();
+    running.retain(|link, (stop, handle)| {
+        let keep = links.iter().any(|l| **l == *link) && !handle.is_finished();
+        if !keep && !handle.is_finished() {
+            info!("{} stopped by config reload", link.local);
+            stop.cancel();
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                sleep(NETWORK_TIMEOUT).await;
+                handle.abort();
+            });
+        }
+        keep
+    });
     for link in links {
-        let join = tokio::spawn(
+        if running.contains_key(&link) {
+            continue;
+        }
+        let link_key = link.clone();
+        let state = state.clone();
+        let stop = state.shutdown.child_token();
+        let link_stop = stop.clone();
+        let handle = joins.spawn(
             async move {
-                run_link_with_retry(link.clone(), port).await;
+                let exit = match state.cfg.get().mode {
+                    Mode::Visitor => run_local_listener(&state, link.clone(), &stop).await,
+                    _ if link.forward.is_some() => {
+                        run_local_listener(&state, link.clone(), &stop).await
+                    }
+                    _ => run_link_with_retry(&state, link.clone(), &stop).await,
+                };
+                state.emit(ClientEvent::Stopped { link, exit });
+                exit
             }
             .instrument(trace_span!("conn", id = Uuid::new_v4().to_string())),
         );
-        joins.push(join);
+        running.insert((*link_key).clone(), (link_stop, handle));
+    }
+}
+
+/// Read the client id from `path`, creating it and its directory on first
+/// run.
+///
+/// If the file cannot be written the id only lives for this process, so ports
+/// are still sticky across reconnects but not across restarts. The full path
+/// is logged, since a `path` given on the command line follows the working
+/// directory.
+fn load_client_id(path: &str) -> String {
+    let shown = std::path::absolute(path).unwrap_or_else(|_| path.into());
+    if let Ok(id) = std::fs::read_to_string(path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            info!("client id {} from {}", id, shown.display());
+            return id.to_string();
+        }
     }
-    for join in joins {
-        let _ = join.await.map_err(|e| error!("{}", e));
+    let id = Uuid::new_v4().to_string();
+    let dir = shown.parent().filter(|d| !d.as_os_str().is_empty());
+    let saved = dir.map_or(Ok(()), std::fs::create_dir_all);
+    match saved.and_then(|()| std::fs::write(path, &id)) {
+        Ok(()) => warn!("new client id {} saved to {}", id, shown.display()),
+        Err(e) => warn!("save client id to {} failed: {}", shown.display(), e),
     }
+    id
 }
 
 /// Keep reconnecting according to retry settings.
 ///
 /// `retry = -1` means retry forever; `retry = 0` means never reconnect.
-async fn run_link_with_retry(link: Arc<Link>, port: u16) {
-    let cfg = G_CFG.get().unwrap();
-    let max_retry = cfg.retry;
-    let interval = cfg.retry_interval;
+/// The delay starts at `retry_interval` and doubles up to `retry_max_interval`;
+/// both the attempt counter and the delay reset once a link was established.
+/// Cancelling `stop` closes the link for good.
+async fn run_link_with_retry(
+    state: &Arc<ClientState>,
+    link: Arc<Link>,
+    stop: &CancellationToken,
+) -> LinkExit {
     let mut attempt: i32 = 0;
 
     loop {
-        match create_link(link.clone(), port).await {
+        let mut established = false;
+        let ret = create_link(state, link.clone(), stop, &mut established).await;
+        if established {
+            state.emit(ClientEvent::Disconnected {
+                link: link.clone(),
+                error: ret.as_ref().err().map(|e| format!("{:#}", e)),
+            });
+        }
+        match ret {
+            Ok(()) if stop.is_cancelled() => {
+                info!("{} closed", link.local);
+                return LinkExit::Closed;
+            }
             Ok(()) => {
-                warn!("{:?}: link closed", link);
+                warn!("{} -> {}: link closed", link.local, link.remote);
             }
             Err(e) => {
-                error!("{:?}:{}", link, e);
+                error!("{} -> {}: {}", link.local, link.remote, e);
+                if let Some(e) = e.downcast_ref::<ErrorInfo>() {
+                    state.emit(ClientEvent::Refused {
+                        link: link.clone(),
+                        error: e.clone(),
+                    });
+                    if !e.kind.is_retryable() {
+                        error!(
+                            "{} -> {}: not retrying: {}",
+                            link.local, link.remote, e.kind
+                        );
+                        return LinkExit::Rejected(e.kind);
+                    }
+                }
             }
         }
 
-        if max_retry == 0 {
-            break;
+        // re-read so a reload applies from the next attempt on
+        let cfg = link.settings(&state.cfg.get());
+        let max_retry = cfg.retry;
+        if max_retry == 0 || stop.is_cancelled() {
+            return LinkExit::Closed;
         }
 
+        if established {
+            attempt = 0;
+        }
         attempt = attempt.saturating_add(1);
         if max_retry > 0 && attempt > max_retry {
-            error!("{:?}: exceeded retry limit ({})", link, max_retry);
-            break;
+            error!(
+                "{} -> {}: exceeded retry limit ({})",
+                link.local, link.remote, max_retry
+            );
+            return LinkExit::Exhausted;
         }
 
         let label = if max_retry < 0 {
@@ -69,49 +476,104 @@ async fn run_link_with_retry(link: Arc<Link>, port: u16) {
         } else {
             format!("attempt {attempt}/{max_retry}")
         };
-        warn!("{:?}: reconnecting in {}s ({})", link, interval, label);
-        sleep(Duration::from_secs(interval)).await;
+        let delay = backoff_delay(attempt, cfg.retry_interval, cfg.retry_max_interval);
+        warn!(
+            "{} -> {}: reconnecting in {:.1}s ({})",
+            link.local,
+            link.remote,
+            delay.as_secs_f64(),
+            label
+        );
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = stop.cancelled() => return LinkExit::Closed,
+        }
     }
 }
 
+/// Delay before reconnect `attempt` (1-based): `base * 2^(attempt - 1)` capped
+/// at `max`, then randomized within its upper half so clients that lost the
+/// same server do not reconnect in lockstep.
+fn backoff_delay(attempt: i32, base: u64, max: u64) -> Duration {
+    let shift = (attempt - 1).clamp(0, 32) as u32;
+    let secs = base.saturating_mul(1 << shift).min(max.max(base));
+    let millis = secs.saturating_mul(1000);
+    Duration::from_millis(rand::rng().random_range(millis / 2..=millis))
+}
+
 /// begin a connect
-async fn create_link(link: Arc<Link>, port: u16) -> Result<()> {
-    let stream = connect_with_timeout(&link.remote.host, port).await?;
+async fn create_link(
+    state: &Arc<ClientState>,
+    link: Arc<Link>,
+    stop: &CancellationToken,
+    established: &mut bool,
+) -> Result<()> {
+    let cfg = link.settings(&state.cfg.get());
+    // loaded again on each reconnect, so a renewed tls_ca takes effect
+    let connector = link.tls.then(|| tls::connector(&link)).transpose()?;
+    let stream =
+        connect_with_timeout(&link.remote.host, cfg.control_port, cfg.connect_timeout).await?;
 
     let mut frame_stream = FrameStream::new(stream);
 
-    let _ = init_port(&mut frame_stream, &link).await?;
+    let remote = init_port(state, &mut frame_stream, &link, &cfg).await?;
+    *established = true;
+    state.emit(ClientEvent::Connected {
+        link: link.clone(),
+        remote,
+    });
 
     let (mut frame_sender, mut frame_receiver) = frame_stream.split();
 
-    tokio::spawn(async move {
+    // held for the life of this link so stopping the link ends the heartbeat
+    let mut heartbeat = JoinSet::new();
+    let closing = stop.clone();
+    heartbeat.spawn(async move {
         loop {
-            sleep(Duration::from_secs(3)).await;
-            if let Err(e) = frame_sender.send(&Msg::Heartbeat).await {
+            let msg = tokio::select! {
+                _ = sleep(Duration::from_secs(3)) => Msg::Heartbeat,
+                _ = closing.cancelled() => Msg::Close,
+            };
+            let close = matches!(msg, Msg::Close);
+            if let Err(e) = frame_sender.send(&msg).await {
                 error!("{}", e);
                 break;
             }
+            if close {
+                break;
+            }
         }
     });
 
     loop {
-        let msg = frame_receiver.recv().await;
+        let msg = tokio::select! {
+            msg = frame_receiver.recv() => msg,
+            _ = stop.cancelled() => {
+                // let the heartbeat task deliver Close before dropping the link
+                let _ = timeout(NETWORK_TIMEOUT, heartbeat.join_next()).await;
+                return Ok(());
+            }
+        };
         let Ok(msg) = msg else {
             bail!("{:?}", msg.unwrap_err());
         };
 
         match msg {
-            Msg::InitPort(_, _) => info!("unexpected init"),
+            Msg::InitPort(..) => info!("unexpected init"),
             Msg::Heartbeat => trace!("server >> heartbeat"),
             Msg::Start => info!("unexpected start on control link"),
+            Msg::Close => info!("unexpected close on control link"),
+            Msg::Visit(..) | Msg::Forward(..) => info!("unexpected request on control link"),
             Msg::Error(e) => {
-                return Err(anyhow!("{}", e));
+                return Err(e.into());
             }
-            Msg::Connect(port, _) => {
+            Msg::Connect(tunnel, _) => {
                 let link = link.clone();
+                let state = state.clone();
+                let connector = connector.clone();
                 tokio::spawn(async move {
                     info!("new connection");
-                    match handle_proxy_connection(port, &link).await {
+                    match handle_proxy_connection(&state, tunnel, &link, connector.as_ref()).await {
                         Ok(_) => info!("connection exited"),
                         Err(err) => warn!("connection exited with error {}", err),
                     }
@@ -121,46 +583,192 @@ async fn create_link(link: Arc<Link>, port: u16) -> Result<()> {
     }
 }
 
-/// send and recv InitPort message with server
-async fn init_port(frame_stream: &mut FrameStream, link: &Arc<Link>) -> Result<()> {
-    let secret = &G_CFG.get().unwrap().secret;
+/// Serve a link whose local side listens: accept on `link.local` and carry
+/// each connection through the server, to the private tunnel `link.name` for
+/// a visitor or to `link.forward` for a forward link.
+async fn run_local_listener(
+    state: &Arc<ClientState>,
+    link: Arc<Link>,
+    stop: &CancellationToken,
+) -> LinkExit {
+    // config checks only let address targets get here
+    let Some(addr) = link.local.addr() else {
+        error!("{} -> {}: cannot listen there", link.local, link.remote);
+        return LinkExit::Rejected(ErrorKind::Internal);
+    };
+    let listener = match TcpListener::bind((addr.host.as_str(), addr.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{} -> {}: listen failed: {}", link.local, link.remote, e);
+            let kind = match e.kind() {
+                io::ErrorKind::AddrInUse => ErrorKind::PortInUse,
+                _ => ErrorKind::Internal,
+            };
+            return LinkExit::Rejected(kind);
+        }
+    };
+    match &link.forward {
+        Some(target) => info!(
+            "{} forwards to {} via {}",
+            link.local, target, link.remote.host
+        ),
+        None => info!(
+            "{} visits {} on {}",
+            link.local,
+            link.name.as_deref().unwrap_or_default(),
+            link.remote.host
+        ),
+    }
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("accept on {} failed: {}", link.local, e);
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => return LinkExit::Closed,
+        };
+        let state = state.clone();
+        let link = link.clone();
+        tokio::spawn(async move {
+            match carry(&state, stream, &link).await {
+                Ok(_) => info!("connection {} exited", peer),
+                Err(err) => warn!("connection {} exited with error {}", peer, err),
+            }
+        });
+    }
+}
+
+/// Carry one connection accepted by [`run_local_listener`].
+async fn carry(state: &ClientState, local: TcpStream, link: &Link) -> Result<()> {
+    let cfg = link.settings(&state.cfg.get());
+    let (tunnel, head) = match &link.forward {
+        Some(target) => {
+            let msg = Msg::Forward(target.host.clone(), target.port, cfg.secret.clone());
+            open_stream(link, &cfg, &msg).await?
+        }
+        None => open_visit(link, &cfg).await?,
+    };
+
+    let _session = state.shutdown.session();
+    let _ = local.set_nodelay(true);
+    if head.is_empty() {
+        proxy(local, tunnel).await?;
+    } else {
+        proxy_with_prepend(local, tunnel, &head).await?;
+    }
+    Ok(())
+}
+
+/// Ask the server for the private tunnel `link.name`; returns the stream to it
+/// and any bytes that already came through.
+pub(crate) async fn open_visit(link: &Link, cfg: &LinkSettings) -> Result<(TcpStream, Vec<u8>)> {
+    let name = link.name.clone().unwrap_or_default();
+    let msg = Msg::Visit(name, cfg.secret.clone(), link.visitor_secret.clone());
+    open_stream(link, cfg, &msg).await
+}
+
+/// Send `msg` on a new connection to the link's server and wait for Start;
+/// returns the stream and any bytes that already came through.
+async fn open_stream(link: &Link, cfg: &LinkSettings, msg: &Msg) -> Result<(TcpStream, Vec<u8>)> {
+    let stream =
+        connect_with_timeout(&link.remote.host, cfg.control_port, cfg.connect_timeout).await?;
+    let _ = stream.set_nodelay(true);
+    let mut frame_stream = FrameStream::new(stream);
+
+    frame_stream.send(msg).await?;
+    match frame_stream.recv_timeout().await? {
+        Msg::Start => Ok(frame_stream.into_tcp_stream()),
+        Msg::Error(e) => Err(e.into()),
+        other => bail!("unexpected msg before start: {:?}", other),
+    }
+}
+
+/// Send InitPort to the server; returns the public address it assigned.
+async fn init_port(
+    state: &ClientState,
+    frame_stream: &mut FrameStream,
+    link: &Arc<Link>,
+    cfg: &LinkSettings,
+) -> Result<Address> {
+    let secret = cfg.secret.clone();
+    let info = LinkInfo {
+        protocol: PROTOCOL_VERSION,
+        client_id: Some(state.client_id.clone()),
+        link_key: Some(link.local.to_string()),
+        bind_ip: link.bind,
+        name: link.name.clone(),
+        description: link.description.clone(),
+        labels: link.labels.clone(),
+        client: Some(ClientInfo {
+            target: link.local.to_string(),
+            ..state.client_info.clone()
+        }),
+        proxy_protocol: link.proxy_protocol,
+        allow: link.allow.clone(),
+        max_conns: link.max_conns,
+        private: link.private,
+        visitor_secret: link.visitor_secret.clone(),
+        public_tls: link.public_tls,
+    };
 
     frame_stream
-        .send(&Msg::InitPort(link.remote.port, secret.clone()))
+        .send(&Msg::InitPort(link.remote.port, secret, Box::new(info)))
         .await?;
-    let msg = frame_stream.recv_timeout().await?;
+    // a server of stab 0.3.4 or earlier cannot decode InitPort and hangs up
+    let msg = frame_stream
+        .recv_timeout()
+        .await
+        .context("no answer to init, the server may run an older stab")?;
     match msg {
-        Msg::InitPort(port, _) => {
-            info!(
-                "{}:{} link to {}:{}",
-                link.local.host, link.local.port, link.remote.host, port
+        Msg::InitPort(_, _, info) if info.protocol != PROTOCOL_VERSION => {
+            let details = format!(
+                "server speaks protocol {}, local {}",
+                info.protocol, PROTOCOL_VERSION
             );
-            Ok(())
+            Err(ErrorInfo::with_details(ErrorKind::ProtocolMismatch, details).into())
+        }
+        Msg::InitPort(port, _, _) => {
+            let remote = Address {
+                host: link.remote.host.clone(),
+                port,
+            };
+            match (&link.name, link.private) {
+                (Some(name), true) => info!("{} link to private tunnel {}", link.local, name),
+                _ => info!("{} link to {}", link.local, remote),
+            }
+            Ok(remote)
         }
-        Msg::Error(e) => Err(anyhow!("{}", e)),
+        Msg::Error(e) => Err(e.into()),
         _ => Err(anyhow!("unexpect msg")),
     }
 }
 
 /// create a TcpStream from to:port
-async fn connect_with_timeout(addr: &str, port: u16) -> Result<TcpStream> {
-    let conn = timeout(NETWORK_TIMEOUT, TcpStream::connect((addr, port)))
+async fn connect_with_timeout(addr: &str, port: u16, limit: Duration) -> Result<TcpStream> {
+    let conn = timeout(limit, TcpStream::connect((addr, port)))
         .await
         .context(format!("{}:{}", addr, port))??;
     Ok(conn)
 }
 
 /// deal connection from server proxy port
-async fn handle_proxy_connection(port: u16, link: &Link) -> Result<()> {
-    let stream = connect_with_timeout(&link.remote.host, G_CFG.get().unwrap().port).await?;
+async fn handle_proxy_connection(
+    state: &ClientState,
+    tunnel: SocketAddr,
+    link: &Link,
+    connector: Option<&TlsConnector>,
+) -> Result<()> {
+    let cfg = link.settings(&state.cfg.get());
+    let stream =
+        connect_with_timeout(&link.remote.host, cfg.control_port, cfg.connect_timeout).await?;
     let _ = stream.set_nodelay(true);
     let mut frame_stream = FrameStream::new(stream);
 
-    let secret = &G_CFG.get().unwrap().secret;
-
-    frame_stream
-        .send(&Msg::Connect(port, secret.clone()))
-        .await?;
+    frame_stream.send(&Msg::Connect(tunnel, cfg.secret)).await?;
 
     // Wait until the server pairs a real client. Connecting to the local
     // target earlier leaves idle SSH (etc.) sessions that get killed, and the
@@ -168,25 +776,148 @@ async fn handle_proxy_connection(port: u16, link: &Link) -> Result<()> {
     //
     // Bound the wait so NAT-killed work connections do not leak tasks forever
     // when the server never sends Start.
-    let msg = timeout(Duration::from_secs(60), frame_stream.recv())
+    let msg = timeout(cfg.start_timeout, frame_stream.recv())
         .await
         .context("timeout waiting for Start")??;
     match msg {
         Msg::Start => {}
-        Msg::Error(e) => return Err(anyhow!("{}", e)),
+        Msg::Error(e) => return Err(e.into()),
         other => return Err(anyhow!("unexpected msg before start: {:?}", other)),
     }
 
-    let (tunnel, head) = frame_stream.into_tcp_stream();
-    let local = connect_with_timeout(&link.local.host, link.local.port).await?;
+    let session = state.shutdown.session();
+    let (stream, head) = frame_stream.into_tcp_stream();
+    if let Some(incoming) = &state.incoming {
+        let stream = TunnelStream::new(stream, head, tunnel, session);
+        return incoming
+            .send(stream)
+            .await
+            .map_err(|_| anyhow!("tunnel listener dropped"));
+    }
+
+    let addr = match &link.local {
+        Target::Tcp(addr) => addr,
+        Target::Socks5 => {
+            let stream = TunnelStream::new(stream, head, tunnel, session);
+            return socks::serve(stream, link, cfg.connect_timeout).await;
+        }
+        #[cfg(unix)]
+        Target::Unix(path) => {
+            let _session = session;
+            let local = connect_unix(path, cfg.connect_timeout).await?;
+            proxy_with_prepend(local, stream, &head).await?;
+            return Ok(());
+        }
+    };
+    let _session = session;
+    let local = connect_with_timeout(&addr.host, addr.port, cfg.connect_timeout).await?;
     let _ = local.set_nodelay(true);
+    if let Some(connector) = connector {
+        let connect = tls::connect(local, connector, link, &addr.host);
+        let local = timeout(cfg.connect_timeout, connect)
+            .await
+            .map_err(|_| anyhow!("TLS to {}: timed out", addr))??;
+        let ret = proxy_with_prepend(local, stream, &head).await;
+        // plenty of servers close without a TLS close_notify
+        let eof = ret
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<io::Error>())
+            .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof);
+        if !eof {
+            ret?;
+        }
+        return Ok(());
+    }
 
     if head.is_empty() {
-        proxy(local, tunnel).await?;
+        proxy(local, stream).await?;
     } else {
         // Rare: bytes already buffered from the tunnel toward local.
-        proxy_with_prepend(local, tunnel, &head).await?;
+        proxy_with_prepend(local, stream, &head).await?;
     }
 
     Ok(())
 }
+
+/// connect to the Unix socket at `path`
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path, limit: Duration) -> Result<UnixStream> {
+    let conn = timeout(limit, UnixStream::connect(path))
+        .await
+        .map_err(|_| anyhow!("unix:{}: timed out", path.display()))?
+        .map_err(|e| anyhow!("unix:{}: {}", path.display(), e))?;
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_from_base() {
+        for (attempt, secs) in [(0, 5), (1, 5), (2, 10), (3, 20), (4, 40)] {
+            let delay = backoff_delay(attempt, 5, 60);
+            let full = Duration::from_secs(secs);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [5, 6, 40, i32::MAX] {
+            let delay = backoff_delay(attempt, 5, 60);
+            assert!(delay >= Duration::from_secs(30), "{attempt}: {delay:?}");
+            assert!(delay <= Duration::from_secs(60), "{attempt}: {delay:?}");
+        }
+        // a cap below the base keeps the base
+        assert!(backoff_delay(3, 10, 1) <= Duration::from_secs(10));
+        assert!(backoff_delay(3, 10, 1) >= Duration::from_secs(5));
+        assert_eq!(backoff_delay(1, 0, 60), Duration::ZERO);
+    }
+
+    #[test]
+    fn exit_codes_follow_the_error_kind() {
+        assert_eq!(
+            LinkExit::Rejected(ErrorKind::Auth).code(),
+            EXIT_NO_PERMISSION
+        );
+        assert_eq!(
+            LinkExit::Rejected(ErrorKind::Banned).code(),
+            EXIT_NO_PERMISSION
+        );
+        assert_eq!(
+            LinkExit::Rejected(ErrorKind::ProtocolMismatch).code(),
+            EXIT_PROTOCOL
+        );
+        assert_eq!(
+            LinkExit::Rejected(ErrorKind::PortOutOfRange).code(),
+            EXIT_CONFIG
+        );
+        assert_eq!(LinkExit::Exhausted.code(), EXIT_RETRY_EXHAUSTED);
+    }
+
+    #[test]
+    fn refusals_as_json() {
+        let link = Arc::new("8000=server.com:9000".parse::<Link>().unwrap());
+        let event = ClientEvent::Refused {
+            link: link.clone(),
+            error: ErrorInfo::with_details(ErrorKind::Banned, "go away"),
+        };
+        assert_eq!(
+            event.to_json(),
+            json!({
+                "event": "refused",
+                "link": "127.0.0.1:8000 -> server.com:9000",
+                "kind": "banned",
+                "details": "go away",
+                "retryable": false,
+            })
+        );
+        let event = ClientEvent::Stopped {
+            link,
+            exit: LinkExit::Rejected(ErrorKind::Banned),
+        };
+        assert_eq!(event.to_json()["exit_code"], 77);
+    }
+}
@@ -1,27 +1,96 @@
 //! the start file
 
 #![forbid(unsafe_code)]
-#![warn(missing_docs)]
 
-use config::G_CFG;
+use std::{sync::Arc, time::Duration};
 
-pub mod config;
-pub mod control;
-pub mod local;
-pub mod server;
-pub mod share;
-pub mod tcp_pool;
-pub mod web;
+use clap::{Parser, ValueEnum};
+use stab::{
+    check,
+    config::{self, build_config, Command, ConfigAction, Mode, StabArgs},
+    connect, shutdown, Client, ClientEvent, Server,
+};
+use tokio::sync::broadcast;
 
 #[tokio::main]
 async fn main() {
-    config::init_config();
-    config::init_log();
-    match G_CFG.get().unwrap().mode {
-        config::Mode::Local => local::run().await,
-        config::Mode::Server => {
-            tokio::spawn(web::run());
-            server::run().await;
+    let mut args = StabArgs::parse();
+    let operands = std::mem::take(&mut args.operands);
+    match (args.command, operands.as_slice()) {
+        (Some(Command::Check), []) => std::process::exit(check::check(&args)),
+        (Some(Command::Config), [action])
+            if matches!(
+                ConfigAction::from_str(action, false),
+                Ok(ConfigAction::Show)
+            ) =>
+        {
+            std::process::exit(check::show(&args))
+        }
+        (Some(Command::Config), _) => {
+            eprintln!("error: usage: stab config show [OPTIONS]");
+            std::process::exit(2);
+        }
+        (Some(Command::Connect), [server, tunnel]) => {
+            std::process::exit(connect::connect(args, server, tunnel).await)
+        }
+        (Some(Command::Connect), _) => {
+            eprintln!("error: usage: stab connect <server> <tunnel> [OPTIONS]");
+            std::process::exit(2);
+        }
+        (_, [arg, ..]) => {
+            eprintln!("error: unexpected argument {:?}", arg);
+            std::process::exit(2);
+        }
+        _ => {}
+    }
+    let cfg = config::init_config(&args);
+    config::init_log(&cfg);
+    let json = args.json;
+    let source = Box::new(move || build_config(&args));
+    match cfg.mode {
+        Mode::Local | Mode::Visitor => {
+            let client = Client::new(cfg).reload_from(source);
+            let printer = json.then(|| tokio::spawn(print_json(client.subscribe())));
+            let client = match client.start().await {
+                Ok(client) => client,
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    std::process::exit(1);
+                }
+            };
+            tokio::spawn(config::reload_on_sighup(Arc::clone(client.config())));
+            tokio::spawn(shutdown::on_signal(client.shutdown_signal()));
+            let code = client.wait().await.code();
+            if let Some(printer) = printer {
+                // the events end with the client; print the last ones
+                let _ = tokio::time::timeout(Duration::from_secs(1), printer).await;
+            }
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Mode::Server => {
+            let server = match Server::new(cfg).reload_from(source).web(true).start().await {
+                Ok(server) => server,
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    return;
+                }
+            };
+            tokio::spawn(config::reload_on_sighup(Arc::clone(server.config())));
+            tokio::spawn(shutdown::on_signal(server.shutdown_signal()));
+            server.wait().await;
+        }
+    }
+}
+
+/// print each client event as a JSON line on stderr
+async fn print_json(mut events: broadcast::Receiver<ClientEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => eprintln!("{}", event.to_json()),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
         }
     }
 }
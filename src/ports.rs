@@ -0,0 +1,143 @@
+//! port sets such as `2000-3000,5000,6000-6100`
+
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+
+/// A set of ports made of comma separated single ports and inclusive ranges.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PortSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortSet {
+    /// a set holding a single range
+    pub fn from_range(range: RangeInclusive<u16>) -> Self {
+        Self {
+            ranges: vec![range],
+        }
+    }
+
+    /// whether `port` is in the set
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|r| r.contains(&port))
+    }
+
+    /// number of ports, counting overlapping segments twice
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(|r| r.len()).sum()
+    }
+
+    /// whether the set holds no port
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the `idx`-th port, walking segments in order
+    pub fn nth(&self, mut idx: usize) -> Option<u16> {
+        for r in &self.ranges {
+            if idx < r.len() {
+                return Some(r.start() + idx as u16);
+            }
+            idx -= r.len();
+        }
+        None
+    }
+
+    /// all ports, walking segments in order
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().flat_map(|r| r.clone())
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let parse = |p: &str| {
+                p.trim()
+                    .parse::<u16>()
+                    .map_err(|e| anyhow!("invalid port {:?} in {:?}: {}", p, part, e))
+            };
+            let range = match part.split_once('-') {
+                Some((min, max)) => parse(min)?..=parse(max)?,
+                None => parse(part)?..=parse(part)?,
+            };
+            if range.is_empty() {
+                bail!("invalid port range {:?}: start is greater than end", part);
+            }
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            bail!("empty port list");
+        }
+        Ok(Self { ranges })
+    }
+}
+
+impl fmt::Display for PortSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, r) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if r.start() == r.end() {
+                write!(f, "{}", r.start())?;
+            } else {
+                write!(f, "{}-{}", r.start(), r.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_segments() {
+        let set: PortSet = " 2000-2002, 5000 ,6000-6001".parse().unwrap();
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [2000, 2001, 2002, 5000, 6000, 6001]
+        );
+        assert_eq!(set.len(), 6);
+        assert_eq!(set.nth(3), Some(5000));
+        assert_eq!(set.nth(6), None);
+        assert!(set.contains(2001) && !set.contains(2003));
+        assert_eq!(set.to_string(), "2000-2002,5000,6000-6001");
+    }
+
+    #[test]
+    fn full_range() {
+        let set: PortSet = "0-65535".parse().unwrap();
+        assert_eq!(set.len(), 65536);
+        assert_eq!(set.nth(65535), Some(65535));
+    }
+
+    #[test]
+    fn empty_parts_are_skipped() {
+        let set: PortSet = "80,,443,".parse().unwrap();
+        assert_eq!(set.to_string(), "80,443");
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for s in [
+            "",
+            " , ",
+            "3000-2000",
+            "-5",
+            "5-",
+            "1-2-3",
+            "65536",
+            "80;443",
+            "http",
+        ] {
+            assert!(s.parse::<PortSet>().is_err(), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! private tunnels, reached by visitors instead of a public port
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use tokio::{net::TcpStream, sync::mpsc};
+
+/// A visitor's connection and the address it came from.
+pub type Visit = (TcpStream, SocketAddr);
+
+/// visitors queued per tunnel before new ones wait
+const VISIT_BACKLOG: usize = 64;
+
+struct Entry {
+    tunnel: SocketAddr,
+    secret: Option<String>,
+    sender: mpsc::Sender<Visit>,
+}
+
+/// Private tunnels by name, each handing visitors to its control loop.
+pub struct PrivateTunnels {
+    map: Mutex<HashMap<String, Entry>>,
+}
+
+impl std::fmt::Debug for PrivateTunnels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let map = self.map.lock().unwrap();
+        f.debug_struct("PrivateTunnels")
+            .field("names", &map.len())
+            .finish()
+    }
+}
+
+impl Default for PrivateTunnels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrivateTunnels {
+    /// create new PrivateTunnels
+    pub fn new() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register `name` for `tunnel`; visitors must give the hashed `secret`.
+    ///
+    /// Returns where the visitors arrive, or None if the name is taken.
+    pub fn insert(
+        &self,
+        name: &str,
+        tunnel: SocketAddr,
+        secret: Option<String>,
+    ) -> Option<mpsc::Receiver<Visit>> {
+        let mut map = self.map.lock().unwrap();
+        if map.contains_key(name) {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel(VISIT_BACKLOG);
+        let entry = Entry {
+            tunnel,
+            secret,
+            sender,
+        };
+        map.insert(name.to_string(), entry);
+        Some(receiver)
+    }
+
+    /// Unregister `name` if it still belongs to `tunnel`.
+    pub fn remove(&self, name: &str, tunnel: SocketAddr) {
+        let mut map = self.map.lock().unwrap();
+        if map.get(name).is_some_and(|e| e.tunnel == tunnel) {
+            map.remove(name);
+        }
+    }
+
+    /// Look up the tunnel a visitor asks for, checking its hashed secret.
+    ///
+    /// The error says why, for the server log only: the visitor gets `Auth`
+    /// either way, so it cannot probe which names exist.
+    pub fn visit(
+        &self,
+        name: &str,
+        secret: Option<&str>,
+    ) -> Result<(SocketAddr, mpsc::Sender<Visit>), &'static str> {
+        let map = self.map.lock().unwrap();
+        let entry = map.get(name).ok_or("no such tunnel")?;
+        if entry.secret.as_deref() != secret {
+            return Err("wrong visitor secret");
+        }
+        Ok((entry.tunnel, entry.sender.clone()))
+    }
+}
@@ -1,23 +1,41 @@
 //! the server mode code
 
 use anyhow::{anyhow, bail, Context, Result};
+use ipnet::IpNet;
 use std::{
-    net::SocketAddr,
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicBool, AtomicU16, Ordering},
-        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
     },
     time::Duration,
 };
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedSender},
-    oneshot,
+    broadcast,
+    mpsc::{self, unbounded_channel, UnboundedSender},
+    oneshot, Semaphore,
 };
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
 
-use crate::share::{proxy_with_prepend, FrameStream, Msg, NETWORK_TIMEOUT, PAIR_TIMEOUT};
-use crate::{config::G_CFG, tcp_pool::TcpPool};
-use crate::{control::CtlConns};
+use crate::control::CtlConns;
+use crate::grace::{ParkedPorts, Reclaimed};
+use crate::private::{PrivateTunnels, Visit};
+use crate::share::{
+    bind_listener, proxy_v1_header, proxy_with_prepend, ClientInfo, ErrorInfo, ErrorKind,
+    FrameStream, LinkInfo, Msg, NETWORK_TIMEOUT, PAIR_TIMEOUT, PROTOCOL_VERSION,
+};
+use crate::shutdown::Shutdown;
+use crate::sticky::StickyPorts;
+use crate::{
+    config::{CfgHandle, ConfigSource, Mode, PortStrategy, StabConfig},
+    tcp_pool::TcpPool,
+    tls::PublicTls,
+};
 use chrono::Local;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -31,6 +49,8 @@ use uuid::Uuid;
 pub struct CtlConInfo {
     /// server port
     pub port: u16,
+    /// address the public port is bound on
+    pub bind: IpAddr,
     /// src address
     pub src: String,
     /// begin time
@@ -41,46 +61,258 @@ pub struct CtlConInfo {
     pub downstream: u64,
     /// transmission data size
     pub total: u64,
+    /// link name given by the local
+    pub name: Option<String>,
+    /// link description given by the local
+    pub description: Option<String>,
+    /// link labels given by the local
+    pub labels: BTreeMap<String, String>,
+    /// build and host details reported by the local
+    pub client: Option<ClientInfo>,
+    /// a private tunnel, reached by visitors under `name` instead of a port
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub private: bool,
 }
 
-/// tcp stream pool
-static TCP_POOL: OnceLock<TcpPool> = OnceLock::new();
+/// most public clients queued on a parked port
+const PARKED_QUEUE_LIMIT: usize = 64;
 
-/// All control connect
-pub static CTL_CONNS: OnceLock<CtlConns> = OnceLock::new();
+/// random picks before falling back to a sequential scan
+const RANDOM_PORT_ATTEMPTS: usize = 64;
 
-/// current port number
-static PORT_IDX: AtomicU16 = AtomicU16::new(0);
+/// events buffered per subscriber before the slowest one misses some
+const EVENT_CAPACITY: usize = 256;
 
-/// Start the server, listening for new control connections.
-pub async fn run() {
-    CTL_CONNS.set(CtlConns::new()).unwrap();
+/// What happens on a running server, see [`ServerHandle::subscribe`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// a local opened a tunnel
+    TunnelOpened {
+        /// public listener of the tunnel
+        tunnel: SocketAddr,
+        /// control connection of the local
+        client: SocketAddr,
+        /// link name given by the local
+        name: Option<String>,
+    },
+    /// a tunnel closed and its port was released
+    TunnelClosed {
+        /// public listener of the tunnel
+        tunnel: SocketAddr,
+    },
+    /// a public client connected to a tunnel
+    Connection {
+        /// public listener of the tunnel
+        tunnel: SocketAddr,
+        /// the public client
+        peer: SocketAddr,
+    },
+    /// a forward link's connection was dialed on the server's network
+    Forwarded {
+        /// the local's connection
+        client: SocketAddr,
+        /// the address dialed
+        target: SocketAddr,
+    },
+}
 
-    TCP_POOL.set(TcpPool::new()).unwrap();
+/// State of one running server, shared by its tasks and its dashboard.
+#[derive(Debug)]
+pub(crate) struct ServerState {
+    /// the server's config
+    pub(crate) cfg: Arc<CfgHandle>,
+    /// shutdown signal and session count
+    pub(crate) shutdown: Arc<Shutdown>,
+    /// all control connections
+    pub(crate) ctl_conns: CtlConns,
+    /// tcp stream pool
+    tcp_pool: TcpPool,
+    /// last port assigned to each client link
+    sticky_ports: StickyPorts,
+    /// ports held for clients that dropped within the reconnect grace period
+    parked_ports: ParkedPorts,
+    /// tunnels reached by visitors only
+    private_tunnels: PrivateTunnels,
+    /// certificate for `public_tls` tunnels
+    public_tls: PublicTls,
+    /// scan cursor into the port range
+    port_idx: AtomicUsize,
+    /// event fan-out
+    events: broadcast::Sender<ServerEvent>,
+}
 
-    let addr = format!("0.0.0.0:{}", G_CFG.get().unwrap().port);
+impl ServerState {
+    fn emit(&self, event: ServerEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+}
 
-    let control_listener = TcpListener::bind(&addr).await;
+/// Builder of a stab server.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let mut cfg = stab::StabConfig::default();
+/// cfg.port = 0;
+/// let server = stab::Server::new(cfg).start().await?;
+/// println!("control port on {}", server.control_addr());
+/// server.wait().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    cfg: StabConfig,
+    source: Option<ConfigSource>,
+    web: bool,
+    events: broadcast::Sender<ServerEvent>,
+}
 
-    let Ok(control_listener) = control_listener else {
-        error!("{}", control_listener.unwrap_err());
-        return;
-    };
+impl Server {
+    /// a server running `cfg`; its `mode` is ignored
+    pub fn new(mut cfg: StabConfig) -> Self {
+        cfg.mode = Mode::Server;
+        Self {
+            cfg,
+            source: None,
+            web: false,
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+        }
+    }
 
-    info!("server listening {}", addr);
+    /// receive [`ServerEvent`]s, including those right after the start
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// rebuild the config from `source` on [`CfgHandle::reload`]
+    pub fn reload_from(mut self, source: ConfigSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// serve the web dashboard on `web_bind_addr:web_port`, off by default
+    pub fn web(mut self, on: bool) -> Self {
+        self.web = on;
+        self
+    }
+
+    /// Bind the listeners and start serving in the background.
+    pub async fn start(self) -> Result<ServerHandle> {
+        let cfg = self.cfg;
+        let control = bind_listener(cfg.bind_addr, cfg.port)
+            .with_context(|| format!("bind control port {}:{}", cfg.bind_addr, cfg.port))?;
+        let web =
+            match self.web {
+                true => Some(bind_listener(cfg.web_bind_addr, cfg.web_port).with_context(
+                    || format!("bind web port {}:{}", cfg.web_bind_addr, cfg.web_port),
+                )?),
+                false => None,
+            };
+        let control_addr = control.local_addr()?;
+        let web_addr = web.as_ref().map(TcpListener::local_addr).transpose()?;
+        let public_tls = PublicTls::new();
+        public_tls.load(&cfg)?;
+
+        let state = Arc::new(ServerState {
+            sticky_ports: StickyPorts::new(cfg.sticky_file.clone()),
+            cfg: Arc::new(CfgHandle::new(cfg, self.source)),
+            shutdown: Arc::new(Shutdown::new()),
+            ctl_conns: CtlConns::new(),
+            tcp_pool: TcpPool::new(),
+            parked_ports: ParkedPorts::new(),
+            private_tunnels: PrivateTunnels::new(),
+            public_tls,
+            port_idx: AtomicUsize::new(0),
+            events: self.events,
+        });
+        if let Some(web) = web {
+            tokio::spawn(crate::web::serve(web, state.clone()));
+        }
+        info!("server listening {}", control_addr);
+        tokio::spawn(reload_tls(state.clone()));
+        let task = tokio::spawn(run(control, state.clone()));
+        Ok(ServerHandle {
+            state,
+            control_addr,
+            web_addr,
+            task,
+        })
+    }
+}
+
+/// A running server started by [`Server::start`].
+#[derive(Debug)]
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+    control_addr: SocketAddr,
+    web_addr: Option<SocketAddr>,
+    task: JoinHandle<()>,
+}
 
+impl ServerHandle {
+    /// address of the control listener
+    pub fn control_addr(&self) -> SocketAddr {
+        self.control_addr
+    }
+
+    /// address of the web dashboard, if enabled
+    pub fn web_addr(&self) -> Option<SocketAddr> {
+        self.web_addr
+    }
+
+    /// the server's config, for reloads and updates
+    pub fn config(&self) -> &Arc<CfgHandle> {
+        &self.state.cfg
+    }
+
+    /// the shutdown signal, e.g. for a signal handler
+    pub fn shutdown_signal(&self) -> Arc<Shutdown> {
+        self.state.shutdown.clone()
+    }
+
+    /// begin a graceful shutdown; [`ServerHandle::wait`] returns once drained
+    pub fn shutdown(&self) {
+        self.state.shutdown.trigger();
+    }
+
+    /// receive [`ServerEvent`]s from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// snapshot of the open tunnels
+    pub fn tunnels(&self) -> Vec<CtlConInfo> {
+        let conns = self.state.ctl_conns.view();
+        conns.iter().map(|c| (**c).clone()).collect()
+    }
+
+    /// wait until the server stopped and drained its sessions
+    pub async fn wait(self) {
+        if let Err(e) = self.task.await {
+            error!("server task failed: {}", e);
+        }
+    }
+}
+
+/// Accept control connections until shutdown, then drain.
+async fn run(control_listener: TcpListener, state: Arc<ServerState>) {
     loop {
-        let ret = control_listener.accept().await;
+        let ret = tokio::select! {
+            ret = control_listener.accept() => ret,
+            _ = state.shutdown.triggered() => break,
+        };
 
         let Ok((stream, addr)) = ret else {
             error!("failed to accept client {}", ret.unwrap_err());
             continue;
         };
 
+        let state = state.clone();
         tokio::spawn(
             async move {
                 info!("incoming connection");
-                if let Err(err) = handle_control_connection(stream, addr).await {
+                if let Err(err) = handle_control_connection(&state, stream, addr).await {
                     warn!("connection {:?} exited with error：{}", addr, err);
                 } else {
                     info!("connection {:?} exited", addr);
@@ -89,73 +321,324 @@ pub async fn run() {
             .instrument(debug_span!("conn", id = Uuid::new_v4().to_string())),
         );
     }
+    drop(control_listener);
+
+    let deadline = Duration::from_secs(state.cfg.get().shutdown_timeout);
+    info!("draining sessions for up to {}s", deadline.as_secs());
+    let left = state.shutdown.drain(deadline).await;
+    if left > 0 {
+        warn!("{} sessions cut off by shutdown", left);
+    }
 }
 
 /// deal with control connection
-async fn handle_control_connection(stream: TcpStream, addr: SocketAddr) -> Result<()> {
+async fn handle_control_connection(
+    state: &Arc<ServerState>,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> Result<()> {
     let mut frame_stream = FrameStream::new(stream);
 
     let msg = frame_stream.recv_timeout().await?;
     match msg {
-        Msg::InitPort(port, secret) => {
-            if !auth(&secret) {
+        Msg::InitPort(port, secret, info) => {
+            if info.protocol != PROTOCOL_VERSION {
+                let details = format!(
+                    "client speaks protocol {}, server {} (stab {})",
+                    info.protocol,
+                    PROTOCOL_VERSION,
+                    env!("CARGO_PKG_VERSION")
+                );
+                warn!("client {}: {}", addr, details);
+                let err = ErrorInfo::with_details(ErrorKind::ProtocolMismatch, details);
+                match info.protocol {
+                    // an old local only decodes text errors, and logs them
+                    0 => frame_stream.send_legacy_error(&err.to_string()).await?,
+                    _ => frame_stream.send(&Msg::Error(err)).await?,
+                }
+                return Ok(());
+            }
+            if !auth(&state.cfg.get(), &secret) {
                 frame_stream
-                    .send(&Msg::Error("auth failed".to_string()))
+                    .send(&Msg::Error(ErrorInfo::new(ErrorKind::Auth)))
                     .await?;
                 bail!("auth failed:{} {:?} {:?}", port, addr, secret);
             }
-            let listener = init_port(&mut frame_stream, port, addr)
-                .await
-                .context("init port failed")?;
+            if let Some(c) = &info.client {
+                if c.version != env!("CARGO_PKG_VERSION") {
+                    warn!(
+                        "client {} ({} {}/{}) runs stab {}, server runs {}",
+                        addr,
+                        c.hostname,
+                        c.os,
+                        c.arch,
+                        c.version,
+                        env!("CARGO_PKG_VERSION")
+                    );
+                }
+            }
+            if info.private && info.public_tls {
+                let details = "private links have no public port to serve TLS on";
+                let err = ErrorInfo::with_details(ErrorKind::ProtocolMismatch, details);
+                frame_stream.send(&Msg::Error(err)).await?;
+                bail!("{:?} asks for public_tls on a private link", addr);
+            }
+            if info.public_tls && state.public_tls.get().is_none() {
+                let err = ErrorInfo::new(ErrorKind::NoCertificate);
+                frame_stream.send(&Msg::Error(err)).await?;
+                bail!("{:?} asks for public_tls, but no tls_cert is set", addr);
+            }
+            if info.private {
+                return handle_private_link(state, frame_stream, &info, addr).await;
+            }
+            let claimant = Claimant {
+                identity: info.identity(),
+                secret: secret.as_deref(),
+                bind_ip: info.bind_ip,
+            };
+            let (listener, queued) =
+                init_port(state, &mut frame_stream, port, claimant, &info, addr)
+                    .await
+                    .context("init port failed")?;
 
-            let port = listener.local_addr().unwrap().port();
+            let tunnel = listener.local_addr().unwrap();
+            state.emit(ServerEvent::TunnelOpened {
+                tunnel,
+                client: addr,
+                name: info.name.clone(),
+            });
 
-            let ret = enter_control_loop(listener, frame_stream, port, addr).await;
-            CTL_CONNS.get().unwrap().remove(port);
-            TCP_POOL.get().unwrap().remove(port);
-            ret?
+            let policy = TunnelPolicy::new(&info);
+            let inbound = Inbound::Public(listener);
+            let ret =
+                enter_control_loop(state, inbound, queued, frame_stream, tunnel, addr, policy)
+                    .await;
+            state.tcp_pool.remove(tunnel);
+            let grace = Duration::from_secs(state.cfg.get().reconnect_grace);
+            match (ret, info.identity()) {
+                (Ok(Some(Inbound::Public(listener))), Some((id, key))) if !grace.is_zero() => {
+                    let owner = format!("{} {}", id, key);
+                    let park = park_port(state.clone(), listener, tunnel, owner, grace);
+                    tokio::spawn(park.in_current_span());
+                }
+                (ret, _) => {
+                    state.ctl_conns.remove(tunnel);
+                    state.emit(ServerEvent::TunnelClosed { tunnel });
+                    ret?;
+                }
+            }
         }
-        Msg::Connect(port, secret) => {
-            if !auth(&secret) {
+        Msg::Connect(tunnel, secret) => {
+            if !auth(&state.cfg.get(), &secret) {
                 frame_stream
-                    .send(&Msg::Error("auth failed".to_string()))
+                    .send(&Msg::Error(ErrorInfo::new(ErrorKind::Auth)))
                     .await?;
-                bail!("auth failed:{} {:?} {:?}", port, addr, secret);
+                bail!("auth failed:{} {:?} {:?}", tunnel, addr, secret);
             }
 
             // Keep the framed stream in the pool until a client is paired and
             // we send Msg::Start — so local does not dial the target early
             // (which breaks SSH and other server-speaks-first protocols).
-            TCP_POOL.get().unwrap().add_frame_stream(port, frame_stream);
+            state.tcp_pool.add_frame_stream(tunnel, frame_stream);
+        }
+        Msg::Visit(name, secret, visitor_secret) => {
+            if !auth(&state.cfg.get(), &secret) {
+                frame_stream
+                    .send(&Msg::Error(ErrorInfo::new(ErrorKind::Auth)))
+                    .await?;
+                bail!("auth failed:{} {:?} {:?}", name, addr, secret);
+            }
+            let (tunnel, visits) = match state
+                .private_tunnels
+                .visit(&name, visitor_secret.as_deref())
+            {
+                Ok(found) => found,
+                Err(reason) => {
+                    let err = ErrorInfo::new(ErrorKind::Auth);
+                    frame_stream.send(&Msg::Error(err)).await?;
+                    bail!("visit {} from {:?} refused: {}", name, addr, reason);
+                }
+            };
+            frame_stream.send(&Msg::Start).await?;
+            let (stream, head) = frame_stream.into_tcp_stream();
+            if !head.is_empty() {
+                bail!("visitor {:?} sent data before Start", addr);
+            }
+            info!("visitor {} -> {} ({})", addr, name, tunnel);
+            visits
+                .send((stream, addr))
+                .await
+                .map_err(|_| anyhow!("private tunnel {} closed", name))?;
+        }
+        Msg::Forward(host, port, secret) => {
+            if !auth(&state.cfg.get(), &secret) {
+                frame_stream
+                    .send(&Msg::Error(ErrorInfo::new(ErrorKind::Auth)))
+                    .await?;
+                bail!("auth failed:{}:{} {:?} {:?}", host, port, addr, secret);
+            }
+            let (target, upstream) = match forward_target(state, &host, port).await {
+                Ok(found) => found,
+                Err(e) => {
+                    frame_stream.send(&Msg::Error(e.clone())).await?;
+                    bail!("forward {:?} to {}:{} refused: {}", addr, host, port, e);
+                }
+            };
+            frame_stream.send(&Msg::Start).await?;
+            info!("forward {} -> {}:{} ({})", addr, host, port, target);
+            state.emit(ServerEvent::Forwarded {
+                client: addr,
+                target,
+            });
+
+            let _session = state.shutdown.session();
+            let (stream, head) = frame_stream.into_tcp_stream();
+            let _ = upstream.set_nodelay(true);
+            proxy_with_prepend(upstream, stream, &head).await?;
         }
         _ => {
+            frame_stream
+                .send(&Msg::Error(ErrorInfo::with_details(
+                    ErrorKind::ProtocolMismatch,
+                    "expected InitPort, Connect, Visit or Forward",
+                )))
+                .await?;
             bail!("unexpect msg:{:?}", msg);
         }
     }
     Ok(())
 }
 
+/// Check `host:port` against `forward_allow` and dial it.
+async fn forward_target(
+    state: &ServerState,
+    host: &str,
+    port: u16,
+) -> Result<(SocketAddr, TcpStream), ErrorInfo> {
+    let rules = state.cfg.get().forward_allow.clone();
+    let target = crate::dest::resolve(&rules, host, port).await?;
+    let unreachable = |e: String| ErrorInfo::with_details(ErrorKind::Unreachable, e);
+    match timeout(NETWORK_TIMEOUT, TcpStream::connect(target)).await {
+        Ok(Ok(stream)) => Ok((target, stream)),
+        Ok(Err(e)) => Err(unreachable(format!("connect {}: {}", target, e))),
+        Err(_) => Err(unreachable(format!("connect {}: timed out", target))),
+    }
+}
+
+/// Serve a private link: register its name for visitors instead of binding a
+/// public port.
+///
+/// The control connection's own address stands in for the tunnel address, so
+/// work connections pair the same way as on a public port.
+async fn handle_private_link(
+    state: &Arc<ServerState>,
+    mut frame_stream: FrameStream,
+    info: &LinkInfo,
+    addr: SocketAddr,
+) -> Result<()> {
+    let Some(name) = &info.name else {
+        let err = ErrorInfo::with_details(ErrorKind::ProtocolMismatch, "private link without name");
+        frame_stream.send(&Msg::Error(err)).await?;
+        bail!("private link from {:?} without name", addr);
+    };
+    let tunnel = addr;
+    let Some(visits) = state
+        .private_tunnels
+        .insert(name, tunnel, info.visitor_secret.clone())
+    else {
+        let err = ErrorInfo::with_details(ErrorKind::NameInUse, name.clone());
+        frame_stream.send(&Msg::Error(err)).await?;
+        bail!("private tunnel name {} in use", name);
+    };
+    info!("private tunnel {} for {}", name, addr);
+    let ret = async {
+        send_init_port(state, &mut frame_stream, tunnel, info, addr).await?;
+        state.emit(ServerEvent::TunnelOpened {
+            tunnel,
+            client: addr,
+            name: Some(name.clone()),
+        });
+        let policy = TunnelPolicy::new(info);
+        let inbound = Inbound::Private(visits);
+        enter_control_loop(
+            state,
+            inbound,
+            Vec::new(),
+            frame_stream,
+            tunnel,
+            addr,
+            policy,
+        )
+        .await
+    }
+    .await;
+    state.private_tunnels.remove(name, tunnel);
+    state.tcp_pool.remove(tunnel);
+    state.ctl_conns.remove(tunnel);
+    state.emit(ServerEvent::TunnelClosed { tunnel });
+    ret.map(|_| ())
+}
+
 /// deal with InitPort message from client
 async fn init_port(
+    state: &ServerState,
     frame_stream: &mut FrameStream,
     port: u16,
+    claimant: Claimant<'_>,
+    info: &LinkInfo,
     addr: SocketAddr,
-) -> Result<TcpListener> {
-    let listener = match create_listener(port).await {
+) -> Result<Reclaimed> {
+    let sticky = claimant.identity;
+    if let Some(reclaimed) = reclaim_port(state, port, sticky).await {
+        let tunnel = reclaimed.0.local_addr().unwrap();
+        info!(
+            "client reclaimed {} with {} queued",
+            tunnel,
+            reclaimed.1.len()
+        );
+        send_init_port(state, frame_stream, tunnel, info, addr).await?;
+        return Ok(reclaimed);
+    }
+    let listener = match create_listener(state, port, claimant).await {
         Ok(listener) => listener,
         Err(e) => {
-            frame_stream
-                .send(&Msg::Error(format!("create control port failed:{}", e)))
-                .await?;
+            frame_stream.send(&Msg::Error(e.clone())).await?;
             error!("{}", e);
-            return Err(anyhow!("{}", e));
+            return Err(e.into());
         }
     };
-    let port = listener.local_addr().unwrap().port();
-    info!("new client {}", port);
+    let tunnel = listener.local_addr().unwrap();
+    info!("new client {}", tunnel);
+    if let Some((id, key)) = sticky {
+        state.sticky_ports.set(id, key, tunnel.port());
+    }
 
+    send_init_port(state, frame_stream, tunnel, info, addr).await?;
+    Ok((listener, Vec::new()))
+}
+
+/// confirm the port to the client and register its connection info
+async fn send_init_port(
+    state: &ServerState,
+    frame_stream: &mut FrameStream,
+    tunnel: SocketAddr,
+    info: &LinkInfo,
+    addr: SocketAddr,
+) -> Result<()> {
+    // a private tunnel has no public port to report
+    let (port, bind) = match info.private {
+        true => (0, IpAddr::from([0, 0, 0, 0])),
+        false => (tunnel.port(), tunnel.ip()),
+    };
     frame_stream
-        .send(&Msg::InitPort(port, None))
+        .send(&Msg::InitPort(
+            port,
+            None,
+            Box::new(LinkInfo {
+                protocol: PROTOCOL_VERSION,
+                ..Default::default()
+            }),
+        ))
         .await
         .context("send init port failed")?;
 
@@ -163,52 +646,260 @@ async fn init_port(
     let time = date.format("%Y-%m-%d %H:%M:%S").to_string();
     let ctl = CtlConInfo {
         port,
+        bind,
         src: addr.to_string(),
         time,
         upstream: 0,
         downstream: 0,
         total: 0,
+        name: info.name.clone(),
+        description: info.description.clone(),
+        labels: info.labels.clone(),
+        client: info.client.clone(),
+        private: info.private,
     };
-    CTL_CONNS.get().unwrap().insert(port, ctl);
-    Ok(listener)
+    state.ctl_conns.insert(tunnel, ctl);
+    Ok(())
+}
+
+/// Take back the port parked for this client identity, if it is still held.
+async fn reclaim_port(
+    state: &ServerState,
+    port: u16,
+    identity: Option<(&str, &str)>,
+) -> Option<Reclaimed> {
+    let (id, key) = identity?;
+    let owner = format!("{} {}", id, key);
+    let (_, handoff) = state.parked_ports.take(&owner, port)?;
+    let (tx, rx) = oneshot::channel();
+    handoff.send(tx).ok()?;
+    rx.await.ok()
+}
+
+/// Keep `listener` bound for `grace` after its control connection dropped.
+///
+/// Public clients are accepted and queued, and handed over together with the
+/// listener if `owner` reconnects in time; otherwise the port is released.
+async fn park_port(
+    state: Arc<ServerState>,
+    listener: TcpListener,
+    tunnel: SocketAddr,
+    owner: String,
+    grace: Duration,
+) {
+    let (handoff, mut request) = oneshot::channel();
+    state.parked_ports.insert(tunnel, owner, handoff);
+    info!("port {} parked for {}s", tunnel, grace.as_secs());
+
+    let deadline = sleep(grace);
+    tokio::pin!(deadline);
+    let mut queue: Vec<TcpStream> = Vec::new();
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = state.shutdown.triggered() => break,
+            reply = &mut request => {
+                if let Ok(reply) = reply {
+                    let _ = reply.send((listener, queue));
+                    return;
+                }
+                break;
+            }
+            conn = listener.accept() => {
+                match conn {
+                    Ok((stream, addr)) if queue.len() < PARKED_QUEUE_LIMIT => {
+                        info!("queued connection {} -> {}", addr, tunnel);
+                        queue.push(stream);
+                    }
+                    Ok((_, addr)) => warn!("queue full, drop {} -> {}", addr, tunnel),
+                    Err(e) => debug!("accept on parked tunnel {} failed: {}", tunnel, e),
+                }
+            }
+            _ = sleep(NETWORK_TIMEOUT) => {
+                // Disconnected from the web page.
+                if !state.ctl_conns.contain(tunnel) {
+                    break;
+                }
+            }
+        }
+    }
+
+    state.parked_ports.remove(tunnel);
+    state.ctl_conns.remove(tunnel);
+    state.emit(ServerEvent::TunnelClosed { tunnel });
+    info!(
+        "port {} released after grace, {} queued dropped",
+        tunnel,
+        queue.len()
+    );
+}
+
+/// Per-tunnel limits the local asked for in [`LinkInfo`].
+#[derive(Debug, Clone)]
+struct TunnelPolicy {
+    proxy_protocol: bool,
+    tls: bool,
+    allow: Vec<IpNet>,
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl TunnelPolicy {
+    fn new(info: &LinkInfo) -> Self {
+        Self {
+            proxy_protocol: info.proxy_protocol,
+            tls: info.public_tls,
+            allow: info.allow.clone(),
+            slots: info.max_conns.map(|n| Arc::new(Semaphore::new(n as usize))),
+        }
+    }
+
+    /// Start proxying a public client, unless the tunnel's allowlist or
+    /// connection limit turns it away.
+    fn spawn_proxy(
+        &self,
+        state: &Arc<ServerState>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        tunnel: SocketAddr,
+        msg_sender: &UnboundedSender<Option<Msg>>,
+    ) {
+        let ip = peer.ip().to_canonical();
+        if !self.allow.is_empty() && !self.allow.iter().any(|n| n.contains(&ip)) {
+            info!("reject {} -> {}: not allowed", peer, tunnel);
+            return;
+        }
+        let permit = match &self.slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    info!("reject {} -> {}: max_conns reached", peer, tunnel);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let header = self
+            .proxy_protocol
+            .then(|| stream.local_addr().map(|dst| proxy_v1_header(peer, dst)))
+            .transpose();
+        let header = match header {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("proxy on {} exited: {}", tunnel, e);
+                return;
+            }
+        };
+
+        state.emit(ServerEvent::Connection { tunnel, peer });
+        let msg_sender = msg_sender.clone();
+        let state = state.clone();
+        let tls = self.tls;
+        tokio::spawn(async move {
+            let _permit = permit;
+            // Finish the handshake before taking a work connection, so a
+            // failed one costs the local nothing.
+            let ret = if tls {
+                match accept_tls(&state, stream).await {
+                    Ok(stream) => pair_and_proxy(&state, stream, tunnel, msg_sender, header).await,
+                    Err(e) => Err(e),
+                }
+            } else {
+                pair_and_proxy(&state, stream, tunnel, msg_sender, header).await
+            };
+            if let Err(e) = ret {
+                warn!("proxy on {} exited: {}", tunnel, e);
+            }
+        });
+    }
+}
+
+/// Terminate TLS on a public client of a `public_tls` tunnel.
+async fn accept_tls(state: &ServerState, client: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let acceptor = state
+        .public_tls
+        .get()
+        .ok_or_else(|| anyhow!("no TLS certificate loaded"))?;
+    timeout(NETWORK_TIMEOUT, acceptor.accept(client))
+        .await
+        .context("timeout in TLS handshake")?
+        .map_err(|e| anyhow!("TLS handshake failed: {}", e))
+}
+
+/// Where the clients of a tunnel come from.
+enum Inbound {
+    /// its public port
+    Public(TcpListener),
+    /// visitors handed over by [`PrivateTunnels::visit`]
+    Private(mpsc::Receiver<Visit>),
+}
+
+impl Inbound {
+    async fn accept(&mut self) -> std::io::Result<Visit> {
+        match self {
+            Inbound::Public(listener) => listener.accept().await,
+            Inbound::Private(visits) => visits.recv().await.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotConnected, "private tunnel closed")
+            }),
+        }
+    }
 }
 
 /// Handle the establishment of data links corresponding to each control port
+///
+/// `queued` are public clients accepted while the port was parked. Returns
+/// `inbound` when the control connection dropped, or `None` when the link was
+/// removed on purpose.
 async fn enter_control_loop(
-    listener: TcpListener,
+    state: &Arc<ServerState>,
+    mut inbound: Inbound,
+    queued: Vec<TcpStream>,
     frame_stream: FrameStream,
-    port: u16,
+    tunnel: SocketAddr,
     addr: SocketAddr,
-) -> Result<()> {
+    policy: TunnelPolicy,
+) -> Result<Option<Inbound>> {
     let (msg_sender, mut msg_recv) = unbounded_channel();
 
     let (mut frame_sender, mut frame_receiver) = frame_stream.split();
 
     // So accept-path waiters see Some(None) instead of None before the first
     // work connection is registered.
-    TCP_POOL.get().unwrap().ensure_port(port);
+    state.tcp_pool.ensure_port(tunnel);
 
     let is_exit = Arc::new(AtomicBool::new(false));
     let is_exit_clone = is_exit.clone();
+    let msg_sender_clone = msg_sender.clone();
+    let recv_state = state.clone();
     tokio::spawn(async move {
         // try to recv the client's heartbeat
-        while let Ok(_) = frame_receiver.recv().await {
+        while let Ok(msg) = frame_receiver.recv().await {
+            if let Msg::Close = msg {
+                info!("client closed {}", tunnel);
+                // Same as a removal from the web page: no grace period.
+                recv_state.ctl_conns.remove(tunnel);
+                break;
+            }
             trace!("{} >> heartbeat", addr.to_string());
 
             let is_exit = is_exit.load(Ordering::Relaxed);
             if is_exit {
-                info!("recv msg loop exit:{}", port);
+                info!("recv msg loop exit:{}", tunnel);
                 break;
             }
         }
+        // The client went away; stop the send loop so the accept loop notices.
+        let _ = msg_sender_clone.send(None);
     });
 
-    // send msg to client
+    // send msg to client; shutdown waits for this to flush its last message
+    let session = state.shutdown.session();
+    let pool_size = state.cfg.get().pool_size as usize;
     tokio::spawn(async move {
+        let _session = session;
         // init tcp stream pool
-        let pool_size = G_CFG.get().unwrap().pool_size as usize;
         for _ in 0..pool_size {
-            if let Err(e) = frame_sender.send(&Msg::Connect(port, None)).await {
+            if let Err(e) = frame_sender.send(&Msg::Connect(tunnel, None)).await {
                 warn!("send msg failed:{}", e);
                 break;
             }
@@ -216,12 +907,12 @@ async fn enter_control_loop(
 
         while let Some(msg) = msg_recv.recv().await {
             let Some(msg) = msg else {
-                info!("send msg loop exit:{}", port);
+                info!("send msg loop exit:{}", tunnel);
                 break;
             };
 
             if let Err(e) = frame_sender.send(&msg).await {
-                warn!("send msg loop exit:{},err:{}", port, e);
+                warn!("send msg loop exit:{},err:{}", tunnel, e);
                 break;
             }
         }
@@ -235,22 +926,40 @@ async fn enter_control_loop(
         loop {
             sleep(Duration::from_secs(15)).await;
             if let Err(e) = msg_sender_clone.send(Some(Msg::Heartbeat)) {
-                info!("send heartbeat loop exit:{} err:{}", port, e);
+                info!("send heartbeat loop exit:{} err:{}", tunnel, e);
                 break;
             }
         }
     });
 
-    loop {
-        // if not existing,exit immediately
-        let exist = CTL_CONNS.get().unwrap().contain(port);
+    for stream in queued {
+        match stream.peer_addr() {
+            Ok(peer) => policy.spawn_proxy(state, stream, peer, tunnel, &msg_sender),
+            Err(e) => debug!("queued connection on {} gone: {}", tunnel, e),
+        }
+    }
 
-        if !exist || msg_sender.is_closed() {
+    let dropped = loop {
+        // if not existing,exit immediately
+        if !state.ctl_conns.contain(tunnel) {
             let _ = msg_sender.send(None);
-            break;
+            break false;
+        }
+        if msg_sender.is_closed() {
+            break true;
+        }
+        if state.shutdown.is_triggered() {
+            let err = ErrorInfo::new(ErrorKind::ServerShuttingDown);
+            let _ = msg_sender.send(Some(Msg::Error(err)));
+            let _ = msg_sender.send(None);
+            break false;
         }
 
-        let proxy_conn = timeout(NETWORK_TIMEOUT, listener.accept()).await;
+        let proxy_conn = tokio::select! {
+            conn = timeout(NETWORK_TIMEOUT, inbound.accept()) => conn,
+            _ = state.shutdown.triggered() => continue,
+            _ = msg_sender.closed() => continue,
+        };
         let Ok(proxy_conn) = proxy_conn else {
             debug!("{}", proxy_conn.unwrap_err());
             continue;
@@ -258,50 +967,51 @@ async fn enter_control_loop(
 
         let (stream, addr) = proxy_conn.context("accept data connect faild")?;
 
-        info!("new connection {}:{}", addr, port);
+        info!("new connection {} -> {}", addr, tunnel);
 
-        let msg_sender = msg_sender.clone();
-        tokio::spawn(async move {
-            if let Err(e) = pair_and_proxy(stream, port, msg_sender).await {
-                warn!("proxy on port {} exited: {}", port, e);
-            }
-        });
-    }
+        policy.spawn_proxy(state, stream, addr, tunnel, &msg_sender);
+    };
 
-    info!("control connect exit:{}", port);
+    info!("control connect exit:{}", tunnel);
 
-    Ok(())
+    Ok(dropped.then_some(inbound))
 }
 
-/// Pair a public client TCP stream with a local work connection and proxy.
-async fn pair_and_proxy(
-    client: TcpStream,
-    port: u16,
+/// Pair a public client stream with a local work connection and proxy.
+///
+/// `header` is written toward the local before any client data.
+async fn pair_and_proxy<C>(
+    state: &Arc<ServerState>,
+    client: C,
+    tunnel: SocketAddr,
     msg_sender: UnboundedSender<Option<Msg>>,
-) -> Result<()> {
-    let pool = TCP_POOL.get().unwrap();
+    header: Option<String>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let pool = &state.tcp_pool;
 
     // Prefer a live idle stream to skip a round-trip. Dead NAT-killed sockets
     // fail Start quickly (timeout); then flush the rest of the idle queue.
-    while let Some(mut frame_stream) = pool.get_frame_stream(port) {
+    if let Some(mut frame_stream) = pool.get_frame_stream(tunnel) {
         match timeout(NETWORK_TIMEOUT, frame_stream.send(&Msg::Start)).await {
             Ok(Ok(())) => {
                 // Refill when we consume a pre-pooled connection.
-                let _ = msg_sender.send(Some(Msg::Connect(port, None)));
-                return finish_proxy(port, client, frame_stream).await;
+                let _ = msg_sender.send(Some(Msg::Connect(tunnel, None)));
+                return finish_proxy(state, tunnel, client, frame_stream, header).await;
             }
-            Ok(Err(e)) => warn!("pooled Start failed on port {}: {}", port, e),
-            Err(_) => warn!("pooled Start timed out on port {}", port),
+            Ok(Err(e)) => warn!("pooled Start failed on {}: {}", tunnel, e),
+            Err(_) => warn!("pooled Start timed out on {}", tunnel),
         }
-        pool.clear_idle(port);
-        break;
+        pool.clear_idle(tunnel);
     }
 
     // Demand a fresh work connection and wait for that specific dial.
     let (tx, rx) = oneshot::channel();
-    pool.add_waiter(port, tx);
+    pool.add_waiter(tunnel, tx);
 
-    if msg_sender.send(Some(Msg::Connect(port, None))).is_err() {
+    if msg_sender.send(Some(Msg::Connect(tunnel, None))).is_err() {
         bail!("control channel closed");
     }
 
@@ -315,19 +1025,50 @@ async fn pair_and_proxy(
         .context("timeout sending Start")?
         .context("send Start failed")?;
 
-    finish_proxy(port, client, frame_stream).await
+    finish_proxy(state, tunnel, client, frame_stream, header).await
 }
 
-async fn finish_proxy(port: u16, client: TcpStream, frame_stream: FrameStream) -> Result<()> {
-    let (proxy_stream, head) = frame_stream.into_tcp_stream();
+async fn finish_proxy<C>(
+    state: &Arc<ServerState>,
+    tunnel: SocketAddr,
+    client: C,
+    frame_stream: FrameStream,
+    header: Option<String>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let _session = state.shutdown.session();
+    let (mut proxy_stream, head) = frame_stream.into_tcp_stream();
+    if let Some(header) = header {
+        proxy_stream.write_all(header.as_bytes()).await?;
+    }
     let (down, up) = proxy_with_prepend(client, proxy_stream, &head).await?;
-    CTL_CONNS.get().unwrap().add_data(port, up, down);
+    state.ctl_conns.add_data(tunnel, up, down);
     Ok(())
 }
 
+/// Load the TLS certificate again after each config reload.
+async fn reload_tls(state: Arc<ServerState>) {
+    let mut reloaded = state.cfg.subscribe();
+    loop {
+        tokio::select! {
+            changed = reloaded.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = state.shutdown.triggered() => break,
+        }
+        if let Err(e) = state.public_tls.load(&state.cfg.get()) {
+            error!("keeping the old TLS certificate: {:#}", e);
+        }
+    }
+}
+
 /// authenticate client
-fn auth(local_secret: &Option<String>) -> bool {
-    let server_secret = &G_CFG.get().unwrap().secret;
+fn auth(cfg: &StabConfig, local_secret: &Option<String>) -> bool {
+    let server_secret = &cfg.secret;
     if local_secret.is_none() && server_secret.is_none() {
         return true;
     }
@@ -341,48 +1082,146 @@ fn auth(local_secret: &Option<String>) -> bool {
     false
 }
 
-/// Atomically claim the next candidate port in `port_range`.
-fn claim_next_port(port_range: &std::ops::RangeInclusive<u16>) -> u16 {
-    let start = *port_range.start();
-    let end = *port_range.end();
-    loop {
-        let cur = PORT_IDX.load(Ordering::Relaxed);
-        let port = if port_range.contains(&cur) { cur } else { start };
-        let next = if port >= end { start } else { port + 1 };
-        if PORT_IDX
-            .compare_exchange_weak(cur, next, Ordering::SeqCst, Ordering::Relaxed)
-            .is_ok()
-        {
-            return port;
+/// Who is asking for a port.
+#[derive(Clone, Copy)]
+struct Claimant<'a> {
+    /// `(client_id, link_key)`
+    identity: Option<(&'a str, &'a str)>,
+    /// hashed secret
+    secret: Option<&'a str>,
+    /// requested bind address, checked against `bindable_ips`
+    bind_ip: Option<IpAddr>,
+}
+
+impl Claimant<'_> {
+    fn client_id(&self) -> Option<&str> {
+        self.identity.map(|(id, _)| id)
+    }
+
+    /// Address to bind the public port on.
+    fn bind_ip(&self, cfg: &StabConfig) -> Result<IpAddr, ErrorInfo> {
+        match self.bind_ip {
+            None => Ok(cfg.data_bind_addr),
+            Some(ip) if ip == cfg.data_bind_addr || cfg.bindable_ips.contains(&ip) => Ok(ip),
+            Some(ip) => Err(ErrorInfo::with_details(
+                ErrorKind::NotAllowed,
+                format!("bind address {}", ip),
+            )),
+        }
+    }
+}
+
+/// Check whether `port` may be handed to `claimant`.
+///
+/// Reserved ports go to their owners only, even outside the port range;
+/// excluded ports are never handed out otherwise.
+fn check_port(cfg: &StabConfig, port: u16, claimant: Claimant) -> Result<(), ErrorInfo> {
+    let mut reserved = false;
+    for r in cfg.reserved.iter().filter(|r| r.ports.contains(port)) {
+        if r.owned_by(claimant.client_id(), claimant.secret) {
+            return Ok(());
         }
+        reserved = true;
     }
+    let reason = if reserved {
+        format!("{} is reserved", port)
+    } else if cfg.exclude_ports.as_ref().is_some_and(|e| e.contains(port)) {
+        format!("{} is excluded", port)
+    } else if !cfg.port_range.contains(port) {
+        format!("{} not in {}", port, cfg.port_range)
+    } else {
+        return Ok(());
+    };
+    Err(ErrorInfo::with_details(ErrorKind::PortOutOfRange, reason))
+}
+
+/// Atomically claim the next candidate index into a port set of `len` ports.
+fn claim_next_index(state: &ServerState, len: usize) -> usize {
+    state.port_idx.fetch_add(1, Ordering::Relaxed) % len
 }
 
 /// create a tcp listener for a port
-async fn create_listener(port: u16) -> Result<TcpListener> {
-    let port_range = &G_CFG.get().unwrap().port_range;
+///
+/// For `port == 0`, a client link seen before gets its previous port back when
+/// that port is still allowed and free; then ports reserved for the client are
+/// tried, then the port range according to `port_strategy`.
+async fn create_listener(
+    state: &ServerState,
+    port: u16,
+    claimant: Claimant<'_>,
+) -> Result<TcpListener, ErrorInfo> {
+    let cfg = state.cfg.get();
+    let cfg = &*cfg;
+    let ip = claimant.bind_ip(cfg)?;
+    let try_bind = |port| try_bind(ip, port);
     if port > 0 {
         // Client requests a specific port number.
-        if !port_range.contains(&port) {
-            return Err(anyhow!("port not in range"));
+        check_port(cfg, port, claimant)?;
+        return try_bind(port).map_err(|e| {
+            let kind = if e.kind() == std::io::ErrorKind::AddrInUse {
+                ErrorKind::PortInUse
+            } else {
+                ErrorKind::Internal
+            };
+            ErrorInfo::with_details(kind, format!("bind {}: {}", port, e))
+        });
+    }
+
+    let last = claimant
+        .identity
+        .and_then(|(id, key)| state.sticky_ports.get(id, key));
+    if let Some(last) = last.filter(|p| check_port(cfg, *p, claimant).is_ok()) {
+        match try_bind(last) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => debug!("sticky port {} unavailable: {}", last, e),
+        }
+    }
+
+    let owned = cfg
+        .reserved
+        .iter()
+        .filter(|r| r.owned_by(claimant.client_id(), claimant.secret));
+    for port in owned.flat_map(|r| r.ports.iter()) {
+        if let Ok(listener) = try_bind(port) {
+            return Ok(listener);
         }
-        return try_bind(port).await;
     }
 
     // Client requests any available port in range.
+    let port_range = &cfg.port_range;
+    let len = port_range.len();
+    if cfg.port_strategy == PortStrategy::Random {
+        for _ in 0..len.min(RANDOM_PORT_ATTEMPTS) {
+            let idx = rand::rng().random_range(0..len);
+            let port = port_range.nth(idx).unwrap();
+            if check_port(cfg, port, claimant).is_err() {
+                continue;
+            }
+            if let Ok(listener) = try_bind(port) {
+                return Ok(listener);
+            }
+        }
+        // Mostly full range: fall back to a scan so a free port is still found.
+    }
+
     // Each candidate is claimed atomically so concurrent allocators do not
     // share the same scan cursor (which could falsely report "not find port").
-    for _ in 0..port_range.len() {
-        let port = claim_next_port(port_range);
-        if let Ok(listener) = try_bind(port).await {
+    for _ in 0..len {
+        let port = port_range.nth(claim_next_index(state, len)).unwrap();
+        if check_port(cfg, port, claimant).is_err() {
+            continue;
+        }
+        if let Ok(listener) = try_bind(port) {
             return Ok(listener);
         }
     }
-    Err(anyhow!("not find port"))
+    Err(ErrorInfo::with_details(
+        ErrorKind::PortInUse,
+        "no free port in range",
+    ))
 }
 
 /// try to bind a port and return TcpListener
-async fn try_bind(port: u16) -> Result<TcpListener> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
-    Ok(listener)
+fn try_bind(ip: IpAddr, port: u16) -> std::io::Result<TcpListener> {
+    bind_listener(ip, port)
 }
@@ -1,6 +1,10 @@
 //! give some generic code
 
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
 use futures::{
@@ -8,10 +12,12 @@ use futures::{
     stream::{SplitSink, SplitStream},
     StreamExt,
 };
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
-    io::{copy_bidirectional, AsyncWriteExt},
-    net::TcpStream,
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
     time::timeout,
 };
 use tokio_util::codec::{AnyDelimiterCodec, Framed};
@@ -21,16 +27,22 @@ pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
 /// How long the server waits to pair a public client with a local work connection.
 pub const PAIR_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// Wire protocol of this build, exchanged in [`LinkInfo::protocol`] and bumped
+/// on incompatible message changes. stab 0.3.4 and earlier speak `0`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 /// Messages exchanged between the Local and the server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Msg {
-    /// init connect,specify port and auth
+    /// init connect,specify port, auth and link identity; stab 0.3.4 and
+    /// earlier send no link identity
     #[serde(rename = "I")]
-    InitPort(u16, Option<String>),
+    InitPort(u16, Option<String>, #[serde(default)] Box<LinkInfo>),
 
     /// Accepts an incoming TCP connection, using this stream as a proxy, and auth.
+    /// The address is the tunnel's public listener on the server.
     #[serde(rename = "C")]
-    Connect(u16, Option<String>),
+    Connect(SocketAddr, Option<String>),
 
     /// Server signals that a client was paired; local should connect to the target.
     #[serde(rename = "S")]
@@ -42,9 +54,221 @@ pub enum Msg {
 
     /// error info
     #[serde(rename = "E")]
-    Error(String),
+    Error(ErrorInfo),
+    /// Local is stopping the link on purpose; the server releases the port
+    /// right away instead of holding it for a reconnect.
+    #[serde(rename = "X")]
+    Close,
+
+    /// A visitor asks for the private tunnel of this name, with the server
+    /// secret and the tunnel's visitor secret. The server answers Start, then
+    /// the stream carries the visitor's connection.
+    #[serde(rename = "V")]
+    Visit(String, Option<String>, Option<String>),
+
+    /// A forward link asks the server to dial this host and port, with the
+    /// server secret. The server answers Start once connected.
+    #[serde(rename = "F")]
+    Forward(String, u16, Option<String>),
+}
+
+/// Identity and options the local sends with [`Msg::InitPort`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkInfo {
+    /// wire protocol of the sender, see [`PROTOCOL_VERSION`]
+    #[serde(default)]
+    pub protocol: u32,
+    /// stable id of the local, persisted on its disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// identifies the link within that client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_key: Option<String>,
+    /// server address to bind the public port on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_ip: Option<IpAddr>,
+    /// short name of the link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// free-text description of the link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// key/value labels of the link
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// what the local reports about itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientInfo>,
+    /// send a PROXY protocol v1 header ahead of each public connection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub proxy_protocol: bool,
+    /// public client networks to accept; empty accepts all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    /// most concurrent public connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_conns: Option<u32>,
+    /// register under `name` for visitors instead of opening a public port
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub private: bool,
+    /// hashed secret visitors must give
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visitor_secret: Option<String>,
+    /// terminate TLS on the public port
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public_tls: bool,
+}
+
+/// Build and host details of a local, reported with [`Msg::InitPort`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientInfo {
+    /// stab version
+    pub version: String,
+    /// host name of the machine
+    pub hostname: String,
+    /// operating system, as in `std::env::consts::OS`
+    pub os: String,
+    /// CPU architecture, as in `std::env::consts::ARCH`
+    pub arch: String,
+    /// local address the link forwards to
+    pub target: String,
+    /// when the local process started
+    pub started: String,
+}
+
+impl ClientInfo {
+    /// details of this process; `target` is left for the caller
+    pub fn current(started: &str) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            target: String::new(),
+            started: started.to_string(),
+        }
+    }
+}
+
+impl LinkInfo {
+    /// `(client_id, link_key)` when both are present
+    pub fn identity(&self) -> Option<(&str, &str)> {
+        match (&self.client_id, &self.link_key) {
+            (Some(id), Some(key)) => Some((id, key)),
+            _ => None,
+        }
+    }
+}
+
+/// Machine-readable reason carried by [`Msg::Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// secret mismatch
+    Auth,
+    /// requested port is already bound, or no free port is left in range
+    PortInUse,
+    /// requested port is outside the server's port range
+    PortOutOfRange,
+    /// the server refuses more tunnels from this client
+    QuotaExceeded,
+    /// the client is not allowed to connect
+    Banned,
+    /// the server is going away
+    ServerShuttingDown,
+    /// unexpected or malformed message
+    ProtocolMismatch,
+    /// another local holds the private tunnel name
+    NameInUse,
+    /// no private tunnel of that name
+    NotFound,
+    /// the destination or bind address is not on the allowlist
+    NotAllowed,
+    /// the destination could not be resolved or reached
+    Unreachable,
+    /// TLS was asked for, but the server has no certificate
+    NoCertificate,
+    /// anything else, including kinds unknown to this build
+    #[serde(other)]
+    Internal,
+}
+
+impl ErrorKind {
+    /// Whether reconnecting with the same config may succeed.
+    pub fn is_retryable(self) -> bool {
+        !matches!(
+            self,
+            ErrorKind::Auth
+                | ErrorKind::PortOutOfRange
+                | ErrorKind::Banned
+                | ErrorKind::ProtocolMismatch
+                | ErrorKind::NotAllowed
+                | ErrorKind::NoCertificate
+        )
+    }
 }
 
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ErrorKind::Auth => "auth failed",
+            ErrorKind::PortInUse => "port in use",
+            ErrorKind::PortOutOfRange => "port not in range",
+            ErrorKind::QuotaExceeded => "quota exceeded",
+            ErrorKind::Banned => "banned",
+            ErrorKind::ServerShuttingDown => "server shutting down",
+            ErrorKind::ProtocolMismatch => "protocol mismatch",
+            ErrorKind::NameInUse => "name in use",
+            ErrorKind::NotFound => "no such tunnel",
+            ErrorKind::NotAllowed => "not allowed",
+            ErrorKind::Unreachable => "destination unreachable",
+            ErrorKind::NoCertificate => "server has no TLS certificate",
+            ErrorKind::Internal => "internal error",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Error sent to the peer: a kind plus optional free-text details.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    /// error kind
+    pub kind: ErrorKind,
+    /// human readable details
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl ErrorInfo {
+    /// create an error without details
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            details: None,
+        }
+    }
+
+    /// create an error with details
+    pub fn with_details(kind: ErrorKind, details: impl ToString) -> Self {
+        Self {
+            kind,
+            details: Some(details.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.details {
+            Some(d) => write!(f, "{}: {}", self.kind, d),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for ErrorInfo {}
+
 /// frame stream, used to send/recv a message
 pub struct FrameStream {
     sender: SplitSink<Framed<TcpStream, AnyDelimiterCodec>, String>,
@@ -87,6 +311,13 @@ impl FrameStream {
         }
     }
 
+    /// Send an error the way stab 0.3.4 and earlier expect it, as plain text.
+    pub async fn send_legacy_error(&mut self, text: &str) -> Result<()> {
+        let msg = serde_json::json!({ "E": text });
+        self.sender.send(msg.to_string()).await?;
+        Ok(())
+    }
+
     /// recv message within the specified time
     pub async fn recv_timeout(&mut self) -> Result<Msg> {
         let msg = timeout(NETWORK_TIMEOUT, self.recv()).await??;
@@ -134,8 +365,49 @@ impl FrameReceiver {
     }
 }
 
-/// Copy data mutually between two Tcpstreams.
-pub async fn proxy(mut stream1: TcpStream, mut stream2: TcpStream) -> Result<(u64, u64)> {
+/// Bind a TCP listener on `ip:port`.
+///
+/// The IPv6 wildcard `::` listens dual-stack (IPv4 and IPv6) regardless of the
+/// platform's `IPV6_V6ONLY` default.
+pub fn bind_listener(ip: IpAddr, port: u16) -> std::io::Result<TcpListener> {
+    let addr = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    // Match `TcpListener::bind`: quick restarts must not hit TIME_WAIT.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// PROXY protocol v1 header for a connection from `src` to `dst`.
+pub fn proxy_v1_header(src: SocketAddr, dst: SocketAddr) -> String {
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    let family = match (src_ip, dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => "TCP4",
+        (IpAddr::V6(_), IpAddr::V6(_)) => "TCP6",
+        _ => return "PROXY UNKNOWN\r\n".to_string(),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src_ip,
+        dst_ip,
+        src.port(),
+        dst.port()
+    )
+}
+
+/// Copy data mutually between two streams.
+pub async fn proxy<A, B>(mut stream1: A, mut stream2: B) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
     let (s1, s2) = copy_bidirectional(&mut stream1, &mut stream2).await?;
     Ok((s1, s2))
 }
@@ -144,11 +416,15 @@ pub async fn proxy(mut stream1: TcpStream, mut stream2: TcpStream) -> Result<(u6
 ///
 /// `prepend` is data already read from `stream2` (codec leftover) that must
 /// still be delivered to the peer on `stream1`.
-pub async fn proxy_with_prepend(
-    mut stream1: TcpStream,
-    mut stream2: TcpStream,
+pub async fn proxy_with_prepend<A, B>(
+    mut stream1: A,
+    mut stream2: B,
     prepend: &[u8],
-) -> Result<(u64, u64)> {
+) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
     let mut extra = 0u64;
     if !prepend.is_empty() {
         stream1.write_all(prepend).await?;
@@ -157,3 +433,55 @@ pub async fn proxy_with_prepend(
     let (s1, s2) = copy_bidirectional(&mut stream1, &mut stream2).await?;
     Ok((s1, s2 + extra))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_init_port_decodes() {
+        // as sent by stab 0.3.4
+        let msg: Msg = serde_json::from_str(r#"{"I":[8000,"secret"]}"#).unwrap();
+        let Msg::InitPort(port, secret, info) = msg else {
+            panic!("{:?}", msg);
+        };
+        assert_eq!(port, 8000);
+        assert_eq!(secret.as_deref(), Some("secret"));
+        assert_eq!(info.protocol, 0);
+    }
+
+    #[test]
+    fn init_port_carries_protocol() {
+        let info = LinkInfo {
+            protocol: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&Msg::InitPort(0, None, Box::new(info))).unwrap();
+        let Msg::InitPort(_, _, info) = serde_json::from_str(&json).unwrap() else {
+            panic!("{}", json);
+        };
+        assert_eq!(info.protocol, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn unknown_error_kind_is_internal() {
+        let e: ErrorInfo = serde_json::from_str(r#"{"kind":"rate_limited"}"#).unwrap();
+        assert_eq!(e.kind, ErrorKind::Internal);
+    }
+
+    #[test]
+    fn error_kinds_on_the_wire() {
+        for (kind, name) in [
+            (ErrorKind::PortInUse, "port_in_use"),
+            (ErrorKind::QuotaExceeded, "quota_exceeded"),
+            (ErrorKind::Banned, "banned"),
+        ] {
+            let json = serde_json::to_string(&ErrorInfo::new(kind)).unwrap();
+            assert_eq!(json, format!(r#"{{"kind":"{}"}}"#, name));
+            let e: ErrorInfo = serde_json::from_str(&json).unwrap();
+            assert_eq!(e.kind, kind);
+        }
+        assert!(ErrorKind::QuotaExceeded.is_retryable());
+        assert!(!ErrorKind::Banned.is_retryable());
+    }
+}
@@ -0,0 +1,122 @@
+//! graceful shutdown
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// Shutdown signal of one server or client, plus a count of sessions to drain
+/// before it stops.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    sessions: AtomicUsize,
+    idle: Notify,
+}
+
+/// Counts as an active session until dropped.
+#[derive(Debug)]
+pub struct SessionGuard(Arc<Shutdown>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if self.0.sessions.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    /// create a Shutdown that has not been triggered
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// start shutting down
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    /// whether shutdown has started
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// resolves once shutdown has started
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// A token cancelled by this shutdown, or earlier on its own, e.g. to
+    /// stop a single link.
+    pub fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// register a session that shutdown should wait for
+    pub fn session(self: &Arc<Self>) -> SessionGuard {
+        self.sessions.fetch_add(1, Ordering::AcqRel);
+        SessionGuard(self.clone())
+    }
+
+    /// Wait until every session ended or `deadline` passed; returns how many
+    /// are still running.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                tokio::pin!(idle);
+                idle.as_mut().enable();
+                if self.sessions.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        let _ = timeout(deadline, wait).await;
+        self.sessions.load(Ordering::Acquire)
+    }
+}
+
+/// Trigger `shutdown` on Ctrl-C, or SIGTERM on unix.
+pub async fn on_signal(shutdown: Arc<Shutdown>) {
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                error!("listen SIGTERM failed: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+
+    tokio::select! {
+        ret = tokio::signal::ctrl_c() => {
+            if let Err(e) = ret {
+                error!("listen Ctrl-C failed: {}", e);
+                return;
+            }
+        }
+        _ = term => {}
+    }
+    info!("shutting down, press Ctrl-C again to exit now");
+    shutdown.trigger();
+
+    if tokio::signal::ctrl_c().await.is_ok() {
+        warn!("forced exit");
+        std::process::exit(130);
+    }
+}
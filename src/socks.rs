@@ -0,0 +1,185 @@
+//! SOCKS5 (RFC 1928) with username/password login (RFC 1929), served by the
+//! local on the connections of a `socks5` link
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::info;
+
+use crate::{config::Link, dest, share::ErrorKind};
+
+const VERSION: u8 = 5;
+/// version of the username/password subnegotiation
+const LOGIN_VERSION: u8 = 1;
+const METHOD_LOGIN: u8 = 2;
+const NO_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// reply codes
+const SUCCEEDED: u8 = 0;
+const NOT_ALLOWED: u8 = 2;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Serve one SOCKS5 client: log it in, dial the destination it asks for if
+/// `link.socks_allow` lets it, then relay until either side closes. Both the
+/// handshake and the dial are bounded by `connect_timeout`.
+pub(crate) async fn serve<S>(mut client: S, link: &Link, connect_timeout: Duration) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // a client that stalls mid-handshake must not hold the connection forever
+    let handshake = async {
+        login(&mut client, link).await?;
+        read_request(&mut client).await
+    };
+    let (host, port) = match timeout(connect_timeout, handshake).await {
+        Ok(request) => request?,
+        Err(_) => bail!("socks5 handshake timed out"),
+    };
+
+    let target = match dest::resolve(&link.socks_allow, &host, port).await {
+        Ok(target) => target,
+        Err(e) => {
+            let code = match e.kind {
+                ErrorKind::NotAllowed => NOT_ALLOWED,
+                _ => HOST_UNREACHABLE,
+            };
+            reply(&mut client, code, None).await?;
+            bail!("socks5 {}:{} refused: {}", host, port, e);
+        }
+    };
+    let mut upstream = match timeout(connect_timeout, TcpStream::connect(target)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            reply(&mut client, CONNECTION_REFUSED, None).await?;
+            bail!("socks5 connect {}: {}", target, e);
+        }
+        Err(_) => {
+            reply(&mut client, HOST_UNREACHABLE, None).await?;
+            bail!("socks5 connect {}: timed out", target);
+        }
+    };
+    let _ = upstream.set_nodelay(true);
+    reply(&mut client, SUCCEEDED, upstream.local_addr().ok()).await?;
+    info!("socks5 {}:{} ({})", host, port, target);
+
+    copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Offer only username/password and check them against the link's.
+async fn login<S>(client: &mut S, link: &Link) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, n] = read_array(client).await?;
+    if version != VERSION {
+        bail!("not a socks5 client (version {})", version);
+    }
+    let mut methods = vec![0; usize::from(n)];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_LOGIN) {
+        client.write_all(&[VERSION, NO_METHOD]).await?;
+        bail!("socks5 client offers no username/password login");
+    }
+    client.write_all(&[VERSION, METHOD_LOGIN]).await?;
+
+    let [version, n] = read_array(client).await?;
+    if version != LOGIN_VERSION {
+        bail!("unknown socks5 login version {}", version);
+    }
+    let user = read_string(client, n).await?;
+    let [n] = read_array(client).await?;
+    let pass = read_string(client, n).await?;
+    let ok = link.socks_user.as_deref() == Some(user.as_str())
+        && link.socks_pass.as_deref() == Some(pass.as_str());
+    client.write_all(&[LOGIN_VERSION, u8::from(!ok)]).await?;
+    if !ok {
+        bail!("socks5 login failed for user {:?}", user);
+    }
+    Ok(())
+}
+
+/// Read a CONNECT request; returns the host, as an address or a name, and
+/// the port.
+async fn read_request<S>(client: &mut S) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, cmd, _, atyp] = read_array(client).await?;
+    if version != VERSION {
+        bail!("unknown socks5 request version {}", version);
+    }
+    let host = match atyp {
+        ATYP_IPV4 => Ipv4Addr::from(read_array::<_, 4>(client).await?).to_string(),
+        ATYP_IPV6 => Ipv6Addr::from(read_array::<_, 16>(client).await?).to_string(),
+        ATYP_DOMAIN => {
+            let [n] = read_array(client).await?;
+            read_string(client, n).await?
+        }
+        _ => {
+            reply(client, ADDRESS_NOT_SUPPORTED, None).await?;
+            bail!("unknown socks5 address type {}", atyp);
+        }
+    };
+    let port = u16::from_be_bytes(read_array(client).await?);
+    if cmd != CMD_CONNECT {
+        reply(client, COMMAND_NOT_SUPPORTED, None).await?;
+        bail!("unsupported socks5 command {} for {}:{}", cmd, host, port);
+    }
+    Ok((host, port))
+}
+
+/// answer a request; `bound` is the local end of the dialed connection
+async fn reply<S>(client: &mut S, code: u8, bound: Option<SocketAddr>) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut buf = vec![VERSION, code, 0];
+    match bound {
+        SocketAddr::V4(a) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&bound.port().to_be_bytes());
+    client.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn read_array<S, const N: usize>(client: &mut S) -> Result<[u8; N]>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0; N];
+    client.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn read_string<S>(client: &mut S, len: u8) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; usize::from(len)];
+    client.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
@@ -0,0 +1,98 @@
+//! sticky port assignment
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+/// Last port handed to each (client id, link key), optionally persisted as JSON
+/// so assignments survive a server restart.
+#[derive(Debug)]
+pub struct StickyPorts {
+    map: Mutex<Ports>,
+    path: Option<String>,
+    /// generation last written to `path`, held while writing
+    saved: Arc<Mutex<u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Ports {
+    map: HashMap<String, u16>,
+    /// bumped on every change
+    generation: u64,
+}
+
+impl StickyPorts {
+    /// create new StickyPorts, loading `path` if it exists
+    pub fn new(path: Option<String>) -> Self {
+        let map = path
+            .as_deref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .and_then(|s| match serde_json::from_str(&s) {
+                Ok(map) => Some(map),
+                Err(e) => {
+                    warn!("ignore invalid sticky file: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            map: Mutex::new(Ports { map, generation: 0 }),
+            path,
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// last port assigned to the link
+    pub fn get(&self, client_id: &str, link_key: &str) -> Option<u16> {
+        let ports = self.map.lock().unwrap();
+        ports.map.get(&key(client_id, link_key)).copied()
+    }
+
+    /// Remember the port assigned to the link, saving the file in the
+    /// background.
+    pub fn set(&self, client_id: &str, link_key: &str, port: u16) {
+        let mut ports = self.map.lock().unwrap();
+        if ports.map.insert(key(client_id, link_key), port) == Some(port) {
+            return;
+        }
+        ports.generation += 1;
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let json = serde_json::to_string_pretty(&ports.map);
+        let generation = ports.generation;
+        drop(ports);
+
+        let saved = self.saved.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut saved = saved.lock().unwrap();
+            // a later change was written already
+            if *saved > generation {
+                return;
+            }
+            let ret = json
+                .map_err(anyhow::Error::from)
+                .and_then(|s| save(&path, &s));
+            match ret {
+                Ok(()) => *saved = generation,
+                Err(e) => warn!("save sticky file {} failed: {}", path, e),
+            }
+        });
+    }
+}
+
+/// Write `contents` to a temporary file next to `path`, then rename it over
+/// `path`, so a crash leaves either the old or the new file.
+fn save(path: &str, contents: &str) -> anyhow::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn key(client_id: &str, link_key: &str) -> String {
+    format!("{} {}", client_id, link_key)
+}
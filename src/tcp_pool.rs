@@ -2,6 +2,7 @@
 
 use std::{
     collections::{HashMap, VecDeque},
+    net::SocketAddr,
     sync::Mutex,
 };
 
@@ -11,12 +12,12 @@ use crate::share::FrameStream;
 
 type Waiter = oneshot::Sender<FrameStream>;
 
-/// Concurrent map of pooled framed streams keyed by exposed port.
+/// Concurrent map of pooled framed streams keyed by exposed address.
 ///
 /// Pending accept waiters are preferred over the idle queue so a freshly dialed
 /// work connection is never stuck behind NAT-killed pooled sockets.
 pub struct TcpPool {
-    map: Mutex<HashMap<u16, PortSlot>>,
+    map: Mutex<HashMap<SocketAddr, PortSlot>>,
 }
 
 struct PortSlot {
@@ -42,6 +43,12 @@ impl std::fmt::Debug for TcpPool {
     }
 }
 
+impl Default for TcpPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TcpPool {
     /// create new TcpPool
     pub fn new() -> Self {
@@ -50,16 +57,16 @@ impl TcpPool {
         }
     }
 
-    /// Ensure `port` has an entry so waiters see an empty queue instead of `None`.
-    pub fn ensure_port(&self, port: u16) {
+    /// Ensure `addr` has an entry so waiters see an empty queue instead of `None`.
+    pub fn ensure_port(&self, addr: SocketAddr) {
         let mut map = self.map.lock().unwrap();
-        map.entry(port).or_insert_with(PortSlot::empty);
+        map.entry(addr).or_insert_with(PortSlot::empty);
     }
 
     /// Deliver a work connection to a pending accept waiter, or park it idle.
-    pub fn add_frame_stream(&self, port: u16, frame_stream: FrameStream) {
+    pub fn add_frame_stream(&self, addr: SocketAddr, frame_stream: FrameStream) {
         let mut map = self.map.lock().unwrap();
-        let slot = map.entry(port).or_insert_with(PortSlot::empty);
+        let slot = map.entry(addr).or_insert_with(PortSlot::empty);
 
         let mut stream = frame_stream;
         while let Some(waiter) = slot.waiters.pop_front() {
@@ -75,12 +82,12 @@ impl TcpPool {
         slot.idle.push_back(stream);
     }
 
-    /// Register a waiter for the next work connection on `port`.
+    /// Register a waiter for the next work connection on `addr`.
     ///
     /// If an idle stream is already available, it is sent on `waiter` immediately.
-    pub fn add_waiter(&self, port: u16, waiter: Waiter) {
+    pub fn add_waiter(&self, addr: SocketAddr, waiter: Waiter) {
         let mut map = self.map.lock().unwrap();
-        let slot = map.entry(port).or_insert_with(PortSlot::empty);
+        let slot = map.entry(addr).or_insert_with(PortSlot::empty);
 
         if let Some(stream) = slot.idle.pop_front() {
             // If the accept task already dropped, park the stream again.
@@ -93,23 +100,23 @@ impl TcpPool {
         slot.waiters.push_back(waiter);
     }
 
-    /// Pop an idle pooled framed stream for `port`, if any.
-    pub fn get_frame_stream(&self, port: u16) -> Option<FrameStream> {
+    /// Pop an idle pooled framed stream for `addr`, if any.
+    pub fn get_frame_stream(&self, addr: SocketAddr) -> Option<FrameStream> {
         let mut map = self.map.lock().unwrap();
-        map.get_mut(&port)?.idle.pop_front()
+        map.get_mut(&addr)?.idle.pop_front()
     }
 
-    /// Drop all idle streams for `port` (e.g. after detecting a dead pooled conn).
-    pub fn clear_idle(&self, port: u16) {
+    /// Drop all idle streams for `addr` (e.g. after detecting a dead pooled conn).
+    pub fn clear_idle(&self, addr: SocketAddr) {
         let mut map = self.map.lock().unwrap();
-        if let Some(slot) = map.get_mut(&port) {
+        if let Some(slot) = map.get_mut(&addr) {
             slot.idle.clear();
         }
     }
 
     /// remove key
-    pub fn remove(&self, port: u16) {
+    pub fn remove(&self, addr: SocketAddr) {
         let mut map = self.map.lock().unwrap();
-        let _ = map.remove(&port);
+        let _ = map.remove(&addr);
     }
 }
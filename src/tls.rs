@@ -0,0 +1,237 @@
+//! TLS toward local targets that only speak it, and on public ports
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            WebPkiServerVerifier,
+        },
+        crypto::{self, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::info;
+
+use crate::config::{Link, StabConfig};
+
+/// Open TLS on `stream`, a connection to the link's local target `host`,
+/// with the link's `connector`.
+pub(crate) async fn connect(
+    stream: TcpStream,
+    connector: &TlsConnector,
+    link: &Link,
+    host: &str,
+) -> Result<TlsStream<TcpStream>> {
+    let name = link.tls_sni.as_deref().unwrap_or(host);
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|_| anyhow!("invalid TLS server name {:?}", name))?;
+    let stream = connector
+        .connect(name, stream)
+        .await
+        .map_err(|e| anyhow!("TLS to {}: {}", host, e))?;
+    Ok(stream)
+}
+
+/// Build the connector of a `tls` link once, to be shared by its connections.
+pub(crate) fn connector(link: &Link) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(link)?)))
+}
+
+/// The client config of a `tls` link: trusting `tls_ca`, or the built-in
+/// roots without it; `tls_insecure` skips verification.
+///
+/// `tls_ca` may also hold the target's own self-signed certificate, which is
+/// then accepted as is.
+pub(crate) fn client_config(link: &Link) -> Result<ClientConfig> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    if link.tls_insecure {
+        let verifier = Arc::new(NoVerifier(provider));
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    let Some(path) = &link.tls_ca else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        return Ok(builder.with_root_certificates(roots).with_no_client_auth());
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("read tls_ca {:?}: {}", path, e))?;
+    let (added, _) = roots.add_parsable_certificates(certs.clone());
+    if added == 0 {
+        bail!("tls_ca {:?} holds no usable certificate", path);
+    }
+    let roots = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+    let verifier = Arc::new(CaVerifier { roots, certs });
+    Ok(builder
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth())
+}
+
+/// verifies against `tls_ca`, taking its certificates as leaves too
+#[derive(Debug)]
+struct CaVerifier {
+    roots: Arc<WebPkiServerVerifier>,
+    certs: Vec<CertificateDer<'static>>,
+}
+
+impl ServerCertVerifier for CaVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        // a self-signed certificate cannot chain to itself
+        if self.certs.iter().any(|c| c == end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.roots
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.roots.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.roots.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.roots.supported_verify_schemes()
+    }
+}
+
+/// accepts any certificate, still checking the handshake signatures
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        let algs = &self.0.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        let algs = &self.0.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Load the certificate chain and key the server terminates TLS with.
+pub(crate) fn acceptor(cert: &str, key: &str) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("read tls_cert {:?}: {}", cert, e))?;
+    if certs.is_empty() {
+        bail!("tls_cert {:?} holds no certificate", cert);
+    }
+    let key =
+        PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow!("read tls_key {:?}: {}", key, e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("tls_cert {:?} and tls_key: {}", cert, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The server's certificate for `public_tls` links, loaded again on reload
+/// so renewed files take effect.
+pub struct PublicTls {
+    acceptor: RwLock<Option<TlsAcceptor>>,
+}
+
+impl std::fmt::Debug for PublicTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PublicTls")
+            .field("loaded", &self.acceptor.read().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl Default for PublicTls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PublicTls {
+    /// create new PublicTls, with no certificate
+    pub fn new() -> Self {
+        Self {
+            acceptor: RwLock::new(None),
+        }
+    }
+
+    /// Load `tls_cert` and `tls_key` of `cfg`, or drop the certificate if
+    /// they are unset; on error the previous one stays.
+    pub fn load(&self, cfg: &StabConfig) -> Result<()> {
+        let acceptor = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => {
+                let acceptor = acceptor(cert, key)?;
+                info!("TLS certificate loaded from {}", cert);
+                Some(acceptor)
+            }
+            _ => None,
+        };
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /// the current certificate, if any
+    pub fn get(&self) -> Option<TlsAcceptor> {
+        self.acceptor.read().unwrap().clone()
+    }
+}
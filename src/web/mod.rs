@@ -1,48 +1,67 @@
 //! the web server to manage the link
 
 use axum::{
-    extract::{Path, Request},
+    extract::{Path, Query, Request, State},
     http::StatusCode,
     middleware::{self, Next},
     response::{Html, Response},
-    routing::{delete, get},
+    routing::{delete, get, post},
     Json, Router,
 };
 
+use serde::Deserialize;
+use std::{net::IpAddr, sync::Arc};
+use tokio::net::TcpListener;
 use tracing::{error, info};
 
 use crate::{
-    config::{hash_key, G_CFG},
-    server::{CtlConInfo, CTL_CONNS},
+    config::{hash_key, Address},
+    server::{CtlConInfo, ServerState},
 };
 
-/// run the web server
-pub async fn run() {
+/// serve the dashboard of `state` on `listener`
+pub(crate) async fn serve(listener: TcpListener, state: Arc<ServerState>) {
     let app = Router::new()
         .route("/", get(root))
         .route("/api/connects", get(get_connects))
         .route("/api/connects/{port}", delete(del_connect))
-        .layer(middleware::from_fn(web_auth));
+        .route("/api/private/{name}", delete(del_private))
+        .route("/api/reload", post(reload))
+        .layer(middleware::from_fn_with_state(state.clone(), web_auth))
+        .with_state(state.clone());
 
-    let port = G_CFG.get().unwrap().web_port;
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await;
-    let Ok(listener) = listener else {
-        error!("start web server failed: {}", listener.unwrap_err());
-        return;
+    let addr = listener.local_addr().ok();
+    let host = match addr {
+        Some(a) if !a.ip().is_unspecified() => a.ip().to_string(),
+        _ => "localhost".to_string(),
     };
-    info!("web server:http://localhost:{}", port);
-    axum::serve(listener, app).await.unwrap();
+    let url = Address {
+        host,
+        port: addr.map_or(0, |a| a.port()),
+    };
+    info!("web server:http://{}", url);
+    let shutdown = state.shutdown.clone();
+    let ret = axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.triggered().await })
+        .await;
+    if let Err(e) = ret {
+        error!("web server exited: {}", e);
+    }
 }
 
 /// Protect API routes when `web_key` is configured. The HTML shell stays open
 /// so the page can prompt for a key.
-async fn web_auth(req: Request, next: Next) -> Result<Response, StatusCode> {
+async fn web_auth(
+    State(state): State<Arc<ServerState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
     let path = req.uri().path();
     if !path.starts_with("/api/") {
         return Ok(next.run(req).await);
     }
 
-    let Some(expected) = G_CFG.get().unwrap().web_key.as_ref() else {
+    let Some(expected) = state.cfg.get().web_key.clone() else {
         return Ok(next.run(req).await);
     };
 
@@ -52,7 +71,7 @@ async fn web_auth(req: Request, next: Next) -> Result<Response, StatusCode> {
         .and_then(|v| v.to_str().ok())
         .map(hash_key);
 
-    if provided.as_ref() == Some(expected) {
+    if provided == Some(expected) {
         Ok(next.run(req).await)
     } else {
         Err(StatusCode::UNAUTHORIZED)
@@ -65,35 +84,93 @@ async fn web_auth(req: Request, next: Next) -> Result<Response, StatusCode> {
 /// Release builds embed the file at compile time.
 async fn root() -> Html<String> {
     #[cfg(debug_assertions)]
-    {
+    let page = {
         let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/web/index.html");
-        Html(std::fs::read_to_string(path).expect("read index.html"))
-    }
+        std::fs::read_to_string(path).expect("read index.html")
+    };
     #[cfg(not(debug_assertions))]
-    {
-        Html(include_str!("index.html").to_string())
-    }
+    let page = include_str!("index.html");
+
+    Html(page.replace("{{version}}", env!("CARGO_PKG_VERSION")))
+}
+
+/// query of [`get_connects`]
+#[derive(Deserialize)]
+struct ConnectsQuery {
+    /// `key=value` keeps tunnels with that label value, `key` those with the label at all
+    label: Option<String>,
 }
 
 /// get all connections
-async fn get_connects() -> Json<Vec<CtlConInfo>> {
-    let conn = CTL_CONNS.get().unwrap().view();
+async fn get_connects(
+    State(state): State<Arc<ServerState>>,
+    Query(q): Query<ConnectsQuery>,
+) -> Json<Vec<CtlConInfo>> {
+    let conn = state.ctl_conns.view();
+    let label = q.label.as_deref().map(|l| match l.split_once('=') {
+        Some((k, v)) => (k.trim(), Some(v.trim())),
+        None => (l.trim(), None),
+    });
     let mut ret = Vec::new();
     for con in conn {
+        if let Some((key, value)) = label {
+            match con.labels.get(key) {
+                Some(v) if value.is_none_or(|value| value == v) => {}
+                _ => continue,
+            }
+        }
         ret.push(CtlConInfo {
             port: con.port,
+            bind: con.bind,
             src: con.src.clone(),
             time: con.time.clone(),
             upstream: con.upstream,
             downstream: con.downstream,
             total: con.total,
+            name: con.name.clone(),
+            description: con.description.clone(),
+            labels: con.labels.clone(),
+            client: con.client.clone(),
+            private: con.private,
         });
     }
     Json(ret)
 }
 
+/// query of [`del_connect`]
+#[derive(Deserialize)]
+struct DelQuery {
+    /// only the tunnel bound on this address; all tunnels on the port if absent
+    bind: Option<IpAddr>,
+}
+
 /// delete a connection
-async fn del_connect(Path(port): Path<u16>) -> StatusCode {
-    CTL_CONNS.get().unwrap().remove(port);
+async fn del_connect(
+    State(state): State<Arc<ServerState>>,
+    Path(port): Path<u16>,
+    Query(q): Query<DelQuery>,
+) -> StatusCode {
+    state
+        .ctl_conns
+        .remove_if(|con| !con.private && con.port == port && q.bind.is_none_or(|b| b == con.bind));
+    StatusCode::OK
+}
+
+/// close a private tunnel
+async fn del_private(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+) -> StatusCode {
+    state
+        .ctl_conns
+        .remove_if(|con| con.private && con.name.as_deref() == Some(name.as_str()));
     StatusCode::OK
 }
+
+/// reload the config file, same as SIGHUP
+async fn reload(State(state): State<Arc<ServerState>>) -> (StatusCode, String) {
+    match state.cfg.reload() {
+        Ok(()) => (StatusCode::OK, String::new()),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    }
+}